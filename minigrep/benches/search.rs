@@ -0,0 +1,60 @@
+// Compares the whole-buffer literal search behind minigrep::search with
+// the line-by-line lines().filter() it replaced. Run with `cargo bench`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use minigrep::{lines, search};
+
+const WORDS: &[&str] = &[
+    "safe", "fast", "productive", "pick", "three", "duct", "tape", "trust", "me", "the",
+    "borrow", "checker", "lifetime", "iterator", "closure", "trait", "object", "vector",
+];
+
+// About 16 MiB of word salad, made the same way on every run. "needle"
+// appears every 10,000 lines and "fast" on a good share of them.
+fn corpus() -> String {
+    let mut contents = String::new();
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut line = 0;
+    while contents.len() < 16 * 1024 * 1024 {
+        line += 1;
+        for _ in 0..10 {
+            // xorshift, so there's no need for a rand dependency
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            contents.push_str(WORDS[(seed % WORDS.len() as u64) as usize]);
+            contents.push(' ');
+        }
+        if line % 10_000 == 0 {
+            contents.push_str("needle");
+        }
+        contents.push('\n');
+    }
+    contents
+}
+
+fn literal_search(c: &mut Criterion) {
+    let contents = corpus();
+    let mut group = c.benchmark_group("literal");
+    group.throughput(Throughput::Bytes(contents.len() as u64));
+    group.sample_size(20);
+
+    for query in ["needle", "fast", "absent"] {
+        group.bench_with_input(BenchmarkId::new("lines_filter", query), query, |b, query| {
+            b.iter(|| {
+                lines(black_box(&contents))
+                    .filter(|m| m.line.contains(query))
+                    .collect::<Vec<_>>()
+                    .len()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("buffer_scan", query), query, |b, query| {
+            b.iter(|| search(query, black_box(&contents)).len())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, literal_search);
+criterion_main!(benches);
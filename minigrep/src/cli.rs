@@ -0,0 +1,922 @@
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+
+use encoding_rs::Encoding;
+use regex::{RegexBuilder, RegexSetBuilder};
+
+use crate::{fields, matcher, walk, Config, MmapChoice};
+
+// Everything a command-line flag can do. Each variant is described once in
+// FLAGS below; the parser and the --help text are both driven by that table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Opt {
+    Regexp,
+    File,
+    Regex,
+    IgnoreCase,
+    NoIgnoreCase,
+    SmartCase,
+    WordRegexp,
+    Multiline,
+    Fuzzy,
+    Index,
+    Follow,
+    Field,
+    LineRegexp,
+    InvertMatch,
+    Count,
+    FilesWithMatches,
+    FilesWithoutMatch,
+    OnlyMatching,
+    ShowPattern,
+    MaxCount,
+    LineNumber,
+    NoLineNumber,
+    ByteOffset,
+    NoByteOffset,
+    AfterContext,
+    BeforeContext,
+    Context,
+    Color,
+    Json,
+    NoJson,
+    Replace,
+    InPlace,
+    DryRun,
+    SearchZip,
+    Text,
+    Encoding,
+    Mmap,
+    NoMmap,
+    Hidden,
+    NoHidden,
+    NoIgnore,
+    Ignore,
+    Glob,
+    Type,
+    TypeNot,
+    TypeAdd,
+    Threads,
+    Help,
+    Version,
+}
+
+// Whether a flag takes an argument. Optional values can only be given
+// inline (--color=always), so a bare --color never swallows the pattern.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    None,
+    Required(&'static str),
+    Optional(&'static str),
+}
+
+struct Flag {
+    opt: Opt,
+    short: Option<char>,
+    long: &'static str,
+    value: Value,
+    help: &'static str,
+}
+
+const FLAGS: &[Flag] = &[
+    Flag { opt: Opt::Regexp, short: Some('e'), long: "regexp", value: Value::Required("PATTERN"), help: "Search for PATTERN; may be given more than once" },
+    Flag { opt: Opt::File, short: Some('f'), long: "file", value: Value::Required("FILE"), help: "Search for each line of FILE as a pattern" },
+    Flag { opt: Opt::Regex, short: Some('E'), long: "regex", value: Value::None, help: "Treat patterns as regular expressions" },
+    Flag { opt: Opt::IgnoreCase, short: Some('i'), long: "ignore-case", value: Value::None, help: "Ignore case distinctions (default if IGNORE_CASE is set)" },
+    Flag { opt: Opt::NoIgnoreCase, short: None, long: "no-ignore-case", value: Value::None, help: "Match case exactly, even if IGNORE_CASE is set" },
+    Flag { opt: Opt::SmartCase, short: Some('S'), long: "smart-case", value: Value::None, help: "Ignore case unless a pattern contains an uppercase letter" },
+    Flag { opt: Opt::WordRegexp, short: Some('w'), long: "word-regexp", value: Value::None, help: "Only match whole words" },
+    Flag { opt: Opt::LineRegexp, short: Some('x'), long: "line-regexp", value: Value::None, help: "Only match whole lines" },
+    Flag { opt: Opt::Multiline, short: Some('U'), long: "multiline", value: Value::None, help: "Let matches span lines; print every line they cover" },
+    Flag { opt: Opt::Fuzzy, short: None, long: "fuzzy", value: Value::Required("K"), help: "Also match text up to K edits from the pattern, noting how many (~K)" },
+    Flag { opt: Opt::Index, short: None, long: "index", value: Value::None, help: "Only search the files the directory's index says may match" },
+    Flag { opt: Opt::Follow, short: None, long: "follow", value: Value::None, help: "Keep searching PATH as lines are appended, like tail -f | grep" },
+    Flag { opt: Opt::Field, short: None, long: "field", value: Value::Required("NAME[=VALUE]"), help: "Search CSV columns or JSON Lines keys (/json/pointer) named NAME" },
+    Flag { opt: Opt::InvertMatch, short: Some('v'), long: "invert-match", value: Value::None, help: "Select lines that do not match" },
+    Flag { opt: Opt::Count, short: Some('c'), long: "count", value: Value::None, help: "Print only a count of selected lines per file" },
+    Flag { opt: Opt::FilesWithMatches, short: Some('l'), long: "files-with-matches", value: Value::None, help: "Print only the names of files with selected lines" },
+    Flag { opt: Opt::FilesWithoutMatch, short: Some('L'), long: "files-without-match", value: Value::None, help: "Print only the names of files without selected lines" },
+    Flag { opt: Opt::OnlyMatching, short: Some('o'), long: "only-matching", value: Value::None, help: "Print only the matched parts of selected lines" },
+    Flag { opt: Opt::ShowPattern, short: None, long: "show-pattern", value: Value::None, help: "Prefix selected lines with the patterns they matched" },
+    Flag { opt: Opt::MaxCount, short: Some('m'), long: "max-count", value: Value::Required("NUM"), help: "Stop reading a file after NUM selected lines" },
+    Flag { opt: Opt::LineNumber, short: Some('n'), long: "line-number", value: Value::None, help: "Prefix each line with its line number" },
+    Flag { opt: Opt::NoLineNumber, short: None, long: "no-line-number", value: Value::None, help: "Don't prefix lines with line numbers" },
+    Flag { opt: Opt::ByteOffset, short: Some('b'), long: "byte-offset", value: Value::None, help: "Prefix each line with its byte offset" },
+    Flag { opt: Opt::NoByteOffset, short: None, long: "no-byte-offset", value: Value::None, help: "Don't prefix lines with byte offsets" },
+    Flag { opt: Opt::AfterContext, short: Some('A'), long: "after-context", value: Value::Required("NUM"), help: "Print NUM lines of context after each match" },
+    Flag { opt: Opt::BeforeContext, short: Some('B'), long: "before-context", value: Value::Required("NUM"), help: "Print NUM lines of context before each match" },
+    Flag { opt: Opt::Context, short: Some('C'), long: "context", value: Value::Required("NUM"), help: "Print NUM lines of context around each match" },
+    Flag { opt: Opt::Color, short: None, long: "color", value: Value::Optional("WHEN"), help: "Color output: never, always or auto (default; honors NO_COLOR)" },
+    Flag { opt: Opt::Json, short: None, long: "json", value: Value::None, help: "Print results as JSON Lines (overrides -c, -l, -L and -o)" },
+    Flag { opt: Opt::NoJson, short: None, long: "no-json", value: Value::None, help: "Print results as text (the default)" },
+    Flag { opt: Opt::Replace, short: Some('r'), long: "replace", value: Value::Required("TEXT"), help: "Print lines with each match replaced by TEXT ($1, ${name} with -E)" },
+    Flag { opt: Opt::InPlace, short: None, long: "in-place", value: Value::None, help: "Write the replacements back to the files instead" },
+    Flag { opt: Opt::DryRun, short: None, long: "dry-run", value: Value::None, help: "Show the changes --in-place would make as a diff" },
+    Flag { opt: Opt::SearchZip, short: Some('z'), long: "search-zip", value: Value::None, help: "Decompress any compressed input, not just .gz/.bz2/.xz/.zst files" },
+    Flag { opt: Opt::Text, short: Some('a'), long: "text", value: Value::None, help: "Search binary files as if they were text" },
+    Flag { opt: Opt::Encoding, short: None, long: "encoding", value: Value::Required("ENC"), help: "Decode input from ENC (latin1, utf-16le, ...) instead of UTF-8" },
+    Flag { opt: Opt::Mmap, short: None, long: "mmap", value: Value::None, help: "Memory map files instead of reading them, whatever their size" },
+    Flag { opt: Opt::NoMmap, short: None, long: "no-mmap", value: Value::None, help: "Never memory map files (by default, ones over 16 MiB are)" },
+    Flag { opt: Opt::Hidden, short: None, long: "hidden", value: Value::None, help: "Search hidden files and directories" },
+    Flag { opt: Opt::NoHidden, short: None, long: "no-hidden", value: Value::None, help: "Skip hidden files and directories (the default)" },
+    Flag { opt: Opt::NoIgnore, short: None, long: "no-ignore", value: Value::None, help: "Don't respect .gitignore and .ignore files" },
+    Flag { opt: Opt::Ignore, short: None, long: "ignore", value: Value::None, help: "Respect .gitignore and .ignore files (the default)" },
+    Flag { opt: Opt::Glob, short: Some('g'), long: "glob", value: Value::Required("GLOB"), help: "Only search paths matching GLOB; !GLOB excludes them" },
+    Flag { opt: Opt::Type, short: Some('t'), long: "type", value: Value::Required("TYPE"), help: "Only search files of TYPE (rust, json, py, ...)" },
+    Flag { opt: Opt::TypeNot, short: Some('T'), long: "type-not", value: Value::Required("TYPE"), help: "Don't search files of TYPE" },
+    Flag { opt: Opt::TypeAdd, short: None, long: "type-add", value: Value::Required("NAME:GLOB"), help: "Add GLOB to the file type NAME" },
+    Flag { opt: Opt::Threads, short: Some('j'), long: "threads", value: Value::Required("NUM"), help: "Search NUM files at once (default: one per CPU)" },
+    Flag { opt: Opt::Help, short: Some('h'), long: "help", value: Value::None, help: "Print this help and exit" },
+    Flag { opt: Opt::Version, short: Some('V'), long: "version", value: Value::None, help: "Print version information and exit" },
+];
+
+// When to color the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorChoice {
+    Never,
+    #[default]
+    Auto,
+    Always,
+}
+
+impl ColorChoice {
+    // Settles Auto into Always or Never. Colors are only worth it when a
+    // person is looking at the output, and NO_COLOR (https://no-color.org)
+    // turns them off unless they were asked for explicitly.
+    pub fn resolve(self, is_terminal: bool) -> ColorChoice {
+        match self {
+            ColorChoice::Auto => {
+                let no_color = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+                if is_terminal && !no_color {
+                    ColorChoice::Always
+                } else {
+                    ColorChoice::Never
+                }
+            }
+            choice => choice,
+        }
+    }
+}
+
+// Everything that can go wrong while turning the command line into a
+// Config. Help and Version aren't failures, but like errors they stop the
+// search from running, so main handles them alongside the real errors.
+#[derive(Debug)]
+pub enum ArgsError {
+    MissingPattern,
+    UnknownFlag(String),
+    MissingValue(String),
+    UnexpectedValue(String),
+    InvalidValue { flag: String, value: String },
+    UnexpectedArgument(String),
+    ConfigFile { path: String, message: String },
+    InvalidFilter(ignore::Error),
+    PatternFile { path: String, err: io::Error },
+    // A flag that only makes sense together with something else
+    Requires { flag: &'static str, needs: &'static str },
+    // A value longer than a flag can take
+    TooLong { flag: &'static str, max: usize },
+    InvalidRegex(regex::Error),
+    Help,
+    Version,
+}
+
+impl fmt::Display for ArgsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArgsError::MissingPattern => write!(f, "Didn't get a query string"),
+            ArgsError::UnknownFlag(flag) => write!(f, "Unknown flag: {}", flag),
+            ArgsError::MissingValue(flag) => write!(f, "Flag {} needs a value", flag),
+            ArgsError::UnexpectedValue(flag) => write!(f, "Flag {} doesn't take a value", flag),
+            ArgsError::InvalidValue { flag, value } => {
+                write!(f, "Invalid value for {}: {}", flag, value)
+            }
+            ArgsError::UnexpectedArgument(arg) => write!(f, "Unexpected argument: {}", arg),
+            ArgsError::PatternFile { path, err } => {
+                write!(f, "Can't read patterns from {}: {}", path, err)
+            }
+            ArgsError::Requires { flag, needs } => write!(f, "Flag {} needs {}", flag, needs),
+            ArgsError::TooLong { flag, max } => {
+                write!(f, "Flag {} needs a pattern of at most {} characters", flag, max)
+            }
+            ArgsError::ConfigFile { path, message } => {
+                write!(f, "Invalid config file {}: {}", path, message)
+            }
+            ArgsError::InvalidFilter(err) => write!(f, "Invalid file filter: {}", err),
+            ArgsError::InvalidRegex(err) => write!(f, "Invalid regular expression: {}", err),
+            ArgsError::Help => write!(f, "{}", usage()),
+            ArgsError::Version => write!(f, "minigrep {}", env!("CARGO_PKG_VERSION")),
+        }
+    }
+}
+
+impl Error for ArgsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ArgsError::InvalidRegex(err) => Some(err),
+            ArgsError::PatternFile { err, .. } => Some(err),
+            ArgsError::InvalidFilter(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+// The --help text, generated from FLAGS so it can't drift from the parser
+pub fn usage() -> String {
+    let names: Vec<String> = FLAGS
+        .iter()
+        .map(|flag| {
+            let short = match flag.short {
+                Some(c) => format!("-{}, ", c),
+                None => "    ".to_string(),
+            };
+            let value = match flag.value {
+                Value::None => String::new(),
+                Value::Required(name) => format!(" {}", name),
+                Value::Optional(name) => format!("[={}]", name),
+            };
+            format!("{}--{}{}", short, flag.long, value)
+        })
+        .collect();
+    let width = names.iter().map(|n| n.len()).max().unwrap_or(0);
+
+    let mut text = String::from(
+        "Usage: minigrep [OPTIONS] PATTERN [PATH]\n       minigrep [OPTIONS] -e PATTERN... [PATH]\n       minigrep [OPTIONS] -f FILE [PATH]\n       minigrep index build [OPTIONS] [DIR]\n\nWith no PATH, or when PATH is -, read standard input.\n\n\
+         minigrep index build writes a trigram index of the files under DIR (default .)\n\
+         to DIR/.minigrep-index; searches of DIR with --index then skip the files that\n\
+         can't match. Running it again only re-reads the files that have changed.\n\n\
+         Default flags can be kept in the file named by MINIGREP_CONFIG: one argument\n\
+         per line, or NAME = VALUE pairs in a .toml file. Only flags for case, context,\n\
+         output (-n, -b, --color, --json), memory maps, threads and which files are\n\
+         searched belong there; patterns and the flags that change what is selected\n\
+         are for the command line. The environment (IGNORE_CASE, NO_COLOR) overrides\n\
+         the config file, and the command line overrides both: --no-json, --no-hidden\n\
+         and the like turn off what the file turned on.\n\n\
+         -i folds case fully for literal patterns, so strasse matches Straße. Regexes\n\
+         (-E) only match other cases a character at a time, so ß is only ß and ẞ.\n\n\
+         --field searches CSV (with a header line) and JSON Lines a record at a time:\n\
+         PATTERN is only looked for in the fields named, and --field NAME=VALUE only\n\
+         selects records where NAME is VALUE; when every field is NAME=VALUE, there\n\
+         is no PATTERN. Each record must be on one line: a quoted CSV field can't hold\n\
+         a line break. Line numbers are those of the file.\n\nOptions:",
+    );
+    for (name, flag) in names.iter().zip(FLAGS) {
+        text.push_str(&format!("\n  {:width$}  {}", name, flag.help, width = width));
+    }
+    text
+}
+
+// What the flags say that isn't kept in Config itself
+#[derive(Default)]
+struct Parsed {
+    // -E: patterns are regexes rather than literals
+    use_regex: bool,
+    // Patterns came from -e or -f, so every positional argument is a path.
+    // Not the same as config.patterns being non-empty: -f may name an
+    // empty file.
+    patterns_given: bool,
+}
+
+// The environment variables that stand in for flags
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Env {
+    // IGNORE_CASE is set: like -i
+    pub ignore_case: bool,
+    // NO_COLOR is set and not empty: like --color=never
+    pub no_color: bool,
+}
+
+// Builds a Config from everywhere settings come from, each overriding the
+// ones before it:
+//   1. the flags in the config file (file_args, see config_file.rs)
+//   2. the environment (env)
+//   3. the arguments after the program name (args)
+// Only the command line may have positional arguments.
+pub fn parse(file_args: Vec<String>, env: Env, args: impl Iterator<Item = String>) -> Result<Config, ArgsError> {
+    let mut config = Config::default();
+    let mut parsed = Parsed::default();
+
+    let stray = parse_flags(&mut config, &mut parsed, file_args.into_iter(), true)?;
+    if let Some(arg) = stray.into_iter().next() {
+        return Err(ArgsError::UnexpectedArgument(format!("{} (in MINIGREP_CONFIG)", arg)));
+    }
+
+    if env.ignore_case {
+        config.ignore_case = true;
+        config.smart_case = false;
+    }
+    if env.no_color {
+        config.color = ColorChoice::Never;
+    }
+
+    let positional = parse_flags(&mut config, &mut parsed, args, false)?;
+
+    // minigrep index build [DIR] writes the trigram index for DIR instead
+    // of searching it; -e index finds the word "index" in a file named build
+    if !parsed.patterns_given && positional.len() >= 2 && positional[0] == "index" && positional[1] == "build" {
+        let mut rest = positional.into_iter().skip(2);
+        config.build_index = true;
+        config.file_path = rest.next().unwrap_or_else(|| String::from("."));
+        if let Some(extra) = rest.next() {
+            return Err(ArgsError::UnexpectedArgument(extra));
+        }
+        walk::check_filters(&config).map_err(ArgsError::InvalidFilter)?;
+        return Ok(config);
+    }
+
+    finish(config, parsed, positional)
+}
+
+// Applies the flags in args to config, returning the positional arguments.
+// in_config is for the flags from the config file, which may only be ones
+// that the command line can take back.
+fn parse_flags(
+    config: &mut Config,
+    parsed: &mut Parsed,
+    mut args: impl Iterator<Item = String>,
+    in_config: bool,
+) -> Result<Vec<String>, ArgsError> {
+    let mut positional = Vec::new();
+    let mut apply = |flag: &Flag, value: Option<String>| {
+        if in_config && !is_default(flag.opt) {
+            return Err(ArgsError::UnexpectedArgument(format!("--{} (in MINIGREP_CONFIG)", flag.long)));
+        }
+        apply(config, parsed, flag, value)
+    };
+
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            // Everything after -- is positional, even if it looks like a flag
+            positional.extend(args.by_ref());
+        } else if let Some(long) = arg.strip_prefix("--") {
+            let (name, inline) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (long, None),
+            };
+            let flag = FLAGS
+                .iter()
+                .find(|flag| flag.long == name)
+                .ok_or_else(|| ArgsError::UnknownFlag(arg.clone()))?;
+
+            let value = match (flag.value, inline) {
+                (Value::None, Some(_)) => return Err(ArgsError::UnexpectedValue(arg)),
+                (Value::None, None) | (Value::Optional(_), None) => None,
+                (_, Some(value)) => Some(value),
+                (Value::Required(_), None) => match args.next() {
+                    Some(value) => Some(value),
+                    None => return Err(ArgsError::MissingValue(arg)),
+                },
+            };
+            apply(flag, value)?;
+        } else if arg.len() > 1 && arg.starts_with('-') {
+            // One or more bundled short flags (-in); a flag that takes a
+            // value uses the rest of the word (-A3) or the next argument
+            for (i, c) in arg.char_indices().skip(1) {
+                let flag = FLAGS
+                    .iter()
+                    .find(|flag| flag.short == Some(c))
+                    .ok_or_else(|| ArgsError::UnknownFlag(format!("-{}", c)))?;
+
+                if let Value::Required(_) = flag.value {
+                    let rest = &arg[i + c.len_utf8()..];
+                    let value = if !rest.is_empty() {
+                        rest.to_string()
+                    } else {
+                        match args.next() {
+                            Some(value) => value,
+                            None => return Err(ArgsError::MissingValue(format!("-{}", c))),
+                        }
+                    };
+                    apply(flag, Some(value))?;
+                    break;
+                }
+                apply(flag, None)?;
+            }
+        } else {
+            positional.push(arg);
+        }
+    }
+
+    Ok(positional)
+}
+
+// Takes the pattern and path from the positional arguments, checks that
+// the flags fit together, and compiles the patterns
+fn finish(mut config: Config, parsed: Parsed, positional: Vec<String>) -> Result<Config, ArgsError> {
+    // Without -e or -f the first positional argument is the pattern, unless
+    // every --field is NAME=VALUE, which says what to look for instead
+    let mut positional = positional.into_iter();
+    let fields_given = !config.fields.is_empty() && config.fields.iter().all(|f| f.contains('='));
+    if !parsed.patterns_given && !fields_given {
+        match positional.next() {
+            Some(pattern) => config.patterns.push(pattern),
+            None => return Err(ArgsError::MissingPattern),
+        }
+    }
+    // No path means standard input, as does an explicit "-"
+    config.file_path = positional.next().unwrap_or_else(|| String::from("-"));
+    if let Some(extra) = positional.next() {
+        return Err(ArgsError::UnexpectedArgument(extra));
+    }
+
+    // Files are rewritten by reading them whole, which standard input
+    // can't be written back to. They are written back as the UTF-8 text
+    // they were read as, so nothing that decodes them first is allowed.
+    for (set, flag) in [(config.in_place, "--in-place"), (config.dry_run, "--dry-run")] {
+        if set && config.replace.is_none() {
+            return Err(ArgsError::Requires { flag, needs: "--replace" });
+        }
+        if set && config.file_path == "-" {
+            return Err(ArgsError::Requires { flag, needs: "a PATH" });
+        }
+        if set && (config.search_zip || config.encoding.is_some() || config.text) {
+            return Err(ArgsError::Requires { flag, needs: "plain UTF-8 files, without -z, --encoding or --text" });
+        }
+    }
+
+    // Following reads a plain file a line at a time, as it's written to
+    if config.follow {
+        if config.file_path == "-" {
+            return Err(ArgsError::Requires { flag: "--follow", needs: "a PATH" });
+        }
+        let whole_file = config.count || config.files_with_matches || config.files_without_match || config.multiline;
+        let rewritten = config.in_place || config.dry_run || config.search_zip || config.encoding.is_some();
+        if whole_file || rewritten {
+            return Err(ArgsError::Requires {
+                flag: "--follow",
+                needs: "line output from plain text, without -c, -l, -L, -U, -z, --encoding or --in-place",
+            });
+        }
+    }
+
+    walk::check_filters(&config).map_err(ArgsError::InvalidFilter)?;
+
+    // -U matches can run over line breaks, so they can't be printed or
+    // replaced a line at a time
+    if config.multiline && (config.only_matching || config.replace.is_some()) {
+        return Err(ArgsError::Requires { flag: "--multiline", needs: "whole lines, without -o or --replace" });
+    }
+
+    // Records are selected whole; there is no one place in the line to
+    // print or replace
+    if !config.fields.is_empty() && (config.multiline || config.only_matching || config.replace.is_some()) {
+        return Err(ArgsError::Requires { flag: "--field", needs: "whole lines, without -o, -U or --replace" });
+    }
+
+    if config.smart_case {
+        config.ignore_case = !config.patterns.iter().any(|p| has_uppercase(p, parsed.use_regex));
+    }
+
+    // Literal patterns are searched for directly, any number at once with
+    // Aho-Corasick: over their case folds with -i, and with -w and -x
+    // checked on every hit.
+    // Anything else is compiled into one regex up front, so a bad pattern
+    // is reported here instead of as a panic halfway through the search.
+    // A whole line is no longer the whole text that is searched
+    let multiline_lines = config.multiline && config.line_regexp;
+    let use_regex = parsed.use_regex;
+    if !config.patterns.is_empty() && (use_regex || multiline_lines) {
+        let patterns: Vec<String> = config
+            .patterns
+            .iter()
+            .map(|p| {
+                let p = if use_regex { format!("(?:{})", p) } else { regex::escape(p) };
+                if config.line_regexp {
+                    format!("^(?:{})$", p)
+                } else if config.word_regexp {
+                    format!(r"\b(?:{})\b", p)
+                } else {
+                    p
+                }
+            })
+            .collect();
+
+        // With -U, ^ and $ still match at the start and end of each line,
+        // \r\n line ends included, as lines() strips them
+        let re = RegexBuilder::new(&patterns.join("|"))
+            .case_insensitive(config.ignore_case)
+            .multi_line(config.multiline)
+            .crlf(config.multiline)
+            .build()
+            .map_err(ArgsError::InvalidRegex)?;
+        config.regex = Some(re);
+
+        // The alternation can't say which of its branches matched
+        if config.show_pattern && patterns.len() > 1 {
+            let set = RegexSetBuilder::new(&patterns)
+                .case_insensitive(config.ignore_case)
+                .multi_line(config.multiline)
+                .crlf(config.multiline)
+                .build()
+                .map_err(ArgsError::InvalidRegex)?;
+            config.regex_set = Some(set);
+        }
+
+        // Only patterns given as regexes have groups worth referring to
+        if !use_regex {
+            config.replace = config.replace.map(|text| text.replace('$', "$$"));
+        }
+    }
+
+    matcher::check(&config)?;
+    Ok(config)
+}
+
+fn apply(config: &mut Config, parsed: &mut Parsed, flag: &Flag, value: Option<String>) -> Result<(), ArgsError> {
+    let value = value.unwrap_or_default();
+    match flag.opt {
+        Opt::Regexp => {
+            config.patterns.push(value);
+            parsed.patterns_given = true;
+        }
+        Opt::File => {
+            let contents = fs::read_to_string(&value)
+                .map_err(|err| ArgsError::PatternFile { path: value, err })?;
+            config.patterns.extend(contents.lines().map(String::from));
+            parsed.patterns_given = true;
+        }
+        Opt::Regex => parsed.use_regex = true,
+        // Whichever of -i, --no-ignore-case and -S comes last wins
+        Opt::IgnoreCase => {
+            config.ignore_case = true;
+            config.smart_case = false;
+        }
+        Opt::NoIgnoreCase => {
+            config.ignore_case = false;
+            config.smart_case = false;
+        }
+        Opt::SmartCase => config.smart_case = true,
+        Opt::WordRegexp => config.word_regexp = true,
+        Opt::LineRegexp => config.line_regexp = true,
+        Opt::Multiline => config.multiline = true,
+        Opt::Fuzzy => config.fuzzy = Some(number(flag, value)?),
+        Opt::Index => config.index = true,
+        Opt::Follow => config.follow = true,
+        Opt::Field => {
+            if !fields::check(&value) {
+                return Err(invalid(flag, value));
+            }
+            config.fields.push(value);
+        }
+        Opt::InvertMatch => config.invert_match = true,
+        Opt::Count => config.count = true,
+        Opt::FilesWithMatches => config.files_with_matches = true,
+        Opt::FilesWithoutMatch => config.files_without_match = true,
+        Opt::OnlyMatching => config.only_matching = true,
+        Opt::ShowPattern => config.show_pattern = true,
+        Opt::MaxCount => config.max_count = Some(number(flag, value)?),
+        Opt::LineNumber => config.line_number = true,
+        Opt::NoLineNumber => config.line_number = false,
+        Opt::ByteOffset => config.byte_offset = true,
+        Opt::NoByteOffset => config.byte_offset = false,
+        Opt::AfterContext => config.after_context = number(flag, value)?,
+        Opt::BeforeContext => config.before_context = number(flag, value)?,
+        Opt::Context => {
+            config.after_context = number(flag, value)?;
+            config.before_context = config.after_context;
+        }
+        Opt::Color => {
+            config.color = match value.as_str() {
+                "never" => ColorChoice::Never,
+                "" | "auto" => ColorChoice::Auto,
+                "always" => ColorChoice::Always,
+                _ => return Err(invalid(flag, value)),
+            }
+        }
+        Opt::Json => config.json = true,
+        Opt::NoJson => config.json = false,
+        Opt::Replace => config.replace = Some(value),
+        Opt::InPlace => config.in_place = true,
+        Opt::DryRun => config.dry_run = true,
+        Opt::SearchZip => config.search_zip = true,
+        Opt::Text => config.text = true,
+        Opt::Encoding => match Encoding::for_label(value.as_bytes()) {
+            Some(encoding) => config.encoding = Some(encoding),
+            None => return Err(invalid(flag, value)),
+        },
+        Opt::Mmap => config.mmap = MmapChoice::Always,
+        Opt::NoMmap => config.mmap = MmapChoice::Never,
+        Opt::Hidden => config.hidden = true,
+        Opt::NoHidden => config.hidden = false,
+        Opt::NoIgnore => config.no_ignore = true,
+        Opt::Ignore => config.no_ignore = false,
+        Opt::Glob => config.globs.push(value),
+        Opt::Type => config.types.push(value),
+        Opt::TypeNot => config.types_not.push(value),
+        Opt::TypeAdd => config.type_defs.push(value),
+        Opt::Threads => {
+            config.threads = number(flag, value.clone())?;
+            if config.threads == 0 {
+                return Err(invalid(flag, value));
+            }
+        }
+        Opt::Help => return Err(ArgsError::Help),
+        Opt::Version => return Err(ArgsError::Version),
+    }
+    Ok(())
+}
+
+// Whether opt can be a default in the config file: the flags for how a
+// search looks and which files it reads, which the command line can undo
+// or override. Patterns, and flags that change what is selected (-v, -c,
+// -E, ...), can't be taken back, so they'd apply to every search.
+fn is_default(opt: Opt) -> bool {
+    matches!(
+        opt,
+        Opt::IgnoreCase
+            | Opt::NoIgnoreCase
+            | Opt::SmartCase
+            | Opt::LineNumber
+            | Opt::NoLineNumber
+            | Opt::ByteOffset
+            | Opt::NoByteOffset
+            | Opt::AfterContext
+            | Opt::BeforeContext
+            | Opt::Context
+            | Opt::Color
+            | Opt::Json
+            | Opt::NoJson
+            | Opt::Mmap
+            | Opt::NoMmap
+            | Opt::Hidden
+            | Opt::NoHidden
+            | Opt::NoIgnore
+            | Opt::Ignore
+            | Opt::Glob
+            | Opt::Type
+            | Opt::TypeNot
+            | Opt::TypeAdd
+            | Opt::Threads
+    )
+}
+
+// Whether pattern asks for a specific case. In a regex, the letter after
+// a backslash (\S, \W, \B, ...) names a class rather than a literal.
+fn has_uppercase(pattern: &str, is_regex: bool) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if is_regex && c == '\\' {
+            chars.next();
+        } else if c.is_uppercase() {
+            return true;
+        }
+    }
+    false
+}
+
+fn number(flag: &Flag, value: String) -> Result<usize, ArgsError> {
+    value.parse().map_err(|_| invalid(flag, value))
+}
+
+fn invalid(flag: &Flag, value: String) -> ArgsError {
+    ArgsError::InvalidValue { flag: format!("--{}", flag.long), value }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_args;
+
+    #[test]
+    fn bundled_short_flags_and_attached_values() {
+        let config = parse_args(&["-inA3", "-B", "1", "to", "poem.txt"]).unwrap();
+
+        assert!(config.ignore_case);
+        assert!(config.line_number);
+        assert_eq!((3, 1), (config.after_context, config.before_context));
+        assert_eq!(vec!["to"], config.patterns);
+        assert_eq!("poem.txt", config.file_path);
+    }
+
+    #[test]
+    fn repeated_patterns_and_double_dash() {
+        let config = parse_args(&["-e", "-v", "--regexp=nobody", "--", "-file"]).unwrap();
+
+        assert!(!config.invert_match);
+        assert_eq!(vec!["-v", "nobody"], config.patterns);
+        assert_eq!("-file", config.file_path);
+        assert!(config.regex.is_none());
+    }
+
+    #[test]
+    fn patterns_from_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("patterns.txt");
+        fs::write(&file, "fn main\r\nstruct Config\n").unwrap();
+        let file = file.to_str().unwrap();
+
+        let config = parse_args(&["-e", "impl", "-f", file, "src"]).unwrap();
+        assert_eq!(vec!["impl", "fn main", "struct Config"], config.patterns);
+        assert_eq!("src", config.file_path);
+
+        let config = parse_args(&["-E", "--show-pattern", "-f", file]).unwrap();
+        assert_eq!(2, config.regex_set.unwrap().len());
+
+        fs::write(dir.path().join("empty.txt"), "").unwrap();
+        let empty = dir.path().join("empty.txt");
+        let config = parse_args(&["-f", empty.to_str().unwrap(), "src"]).unwrap();
+        assert!(config.patterns.is_empty());
+        assert_eq!("src", config.file_path);
+
+        assert!(matches!(
+            parse_args(&["-f", "/nonexistent/patterns"]),
+            Err(ArgsError::PatternFile { .. })
+        ));
+    }
+
+    #[test]
+    fn mmap_choice() {
+        assert_eq!(MmapChoice::Auto, parse_args(&["to", "poem.txt"]).unwrap().mmap);
+        assert_eq!(MmapChoice::Always, parse_args(&["--mmap", "to", "poem.txt"]).unwrap().mmap);
+        assert_eq!(MmapChoice::Never, parse_args(&["--mmap", "--no-mmap", "to", "poem.txt"]).unwrap().mmap);
+    }
+
+    #[test]
+    fn flags_override_environment_default() {
+        let env = Env { ignore_case: true, no_color: true };
+
+        let args = ["--no-ignore-case", "to", "poem.txt"].map(String::from);
+        assert!(!parse(Vec::new(), env, args.into_iter()).unwrap().ignore_case);
+
+        let args = ["to", "poem.txt"].map(String::from);
+        let config = parse(Vec::new(), env, args.into_iter()).unwrap();
+        assert!(config.ignore_case);
+        assert_eq!(ColorChoice::Never, config.color);
+    }
+
+    #[test]
+    fn config_file_is_overridden_by_env_and_flags() {
+        let file_args = ["--smart-case", "--color=always", "-n", "--glob=!target/**"].map(String::from).to_vec();
+        let env = Env { ignore_case: true, no_color: false };
+
+        let config = parse(file_args.clone(), Env::default(), ["To", "p"].map(String::from).into_iter()).unwrap();
+        assert!(config.smart_case && !config.ignore_case);
+        assert_eq!(ColorChoice::Always, config.color);
+        assert!(config.line_number);
+        assert_eq!(vec!["!target/**"], config.globs);
+
+        let config = parse(file_args.clone(), env, ["To", "p"].map(String::from).into_iter()).unwrap();
+        assert!(!config.smart_case && config.ignore_case);
+
+        let args = ["-S", "--color=never", "To", "p"].map(String::from);
+        let config = parse(file_args, env, args.into_iter()).unwrap();
+        assert!(config.smart_case && !config.ignore_case);
+        assert_eq!(ColorChoice::Never, config.color);
+
+        assert!(matches!(
+            parse(vec!["stray".to_string()], env, ["to"].map(String::from).into_iter()),
+            Err(ArgsError::UnexpectedArgument(arg)) if arg.contains("MINIGREP_CONFIG")
+        ));
+    }
+
+    #[test]
+    fn command_line_turns_off_what_the_config_file_turns_on() {
+        let file_args = ["--json", "-nb", "--hidden", "--no-ignore"].map(String::from).to_vec();
+        let args = ["--no-json", "--no-line-number", "--no-byte-offset", "--no-hidden", "--ignore", "to", "p"];
+
+        let config = parse(file_args.clone(), Env::default(), ["to", "p"].map(String::from).into_iter()).unwrap();
+        assert!(config.json && config.line_number && config.byte_offset && config.hidden && config.no_ignore);
+        let config = parse(file_args, Env::default(), args.map(String::from).into_iter()).unwrap();
+        assert!(!config.json && !config.line_number && !config.byte_offset && !config.hidden && !config.no_ignore);
+    }
+
+    #[test]
+    fn config_file_holds_no_patterns_or_selection_flags() {
+        let args = || ["fast", "poem.txt"].map(String::from).into_iter();
+        for file_args in [&["-e", "TODO"][..], &["--file=pats.txt"], &["-v"], &["-nc"], &["--regex"]] {
+            let file_args = file_args.iter().map(|s| s.to_string()).collect();
+            assert!(matches!(
+                parse(file_args, Env::default(), args()),
+                Err(ArgsError::UnexpectedArgument(arg)) if arg.contains("MINIGREP_CONFIG")
+            ));
+        }
+    }
+
+    #[test]
+    fn color_value_is_optional_and_checked() {
+        assert_eq!(ColorChoice::Auto, parse_args(&["--color", "to", "p"]).unwrap().color);
+        assert_eq!(ColorChoice::Always, parse_args(&["--color=always", "to", "p"]).unwrap().color);
+        assert!(matches!(
+            parse_args(&["--color=sometimes", "to", "p"]),
+            Err(ArgsError::InvalidValue { .. })
+        ));
+    }
+
+    #[test]
+    fn encoding_takes_a_label() {
+        let config = parse_args(&["--encoding", "latin1", "to", "p"]).unwrap();
+        assert_eq!(Some(encoding_rs::WINDOWS_1252), config.encoding);
+        assert!(matches!(
+            parse_args(&["--encoding=klingon", "to", "p"]),
+            Err(ArgsError::InvalidValue { .. })
+        ));
+    }
+
+    #[test]
+    fn auto_color_follows_the_terminal() {
+        assert_eq!(ColorChoice::Never, ColorChoice::Auto.resolve(false));
+        assert_eq!(ColorChoice::Always, ColorChoice::Always.resolve(false));
+        assert_eq!(ColorChoice::Never, ColorChoice::Never.resolve(true));
+    }
+
+    #[test]
+    fn reports_typed_errors() {
+        assert!(matches!(parse_args(&[]), Err(ArgsError::MissingPattern)));
+        assert_eq!("-", parse_args(&["to"]).unwrap().file_path);
+        assert!(matches!(parse_args(&["-q", "to", "p"]), Err(ArgsError::UnknownFlag(f)) if f == "-q"));
+        assert!(matches!(parse_args(&["to", "p", "-A"]), Err(ArgsError::MissingValue(_))));
+        assert!(matches!(parse_args(&["--count=1", "to", "p"]), Err(ArgsError::UnexpectedValue(_))));
+        assert!(matches!(parse_args(&["to", "p", "q"]), Err(ArgsError::UnexpectedArgument(_))));
+        assert!(matches!(parse_args(&["--help"]), Err(ArgsError::Help)));
+        assert!(matches!(parse_args(&["-V"]), Err(ArgsError::Version)));
+        assert!(matches!(parse_args(&["-t", "klingon", "to"]), Err(ArgsError::InvalidFilter(_))));
+        assert!(matches!(parse_args(&["-g", "src/[", "to"]), Err(ArgsError::InvalidFilter(_))));
+        assert!(matches!(parse_args(&["--fuzzy", "x", "to"]), Err(ArgsError::InvalidValue { .. })));
+        assert!(matches!(
+            parse_args(&["--fuzzy", "1", "-E", "t.", "p"]),
+            Err(ArgsError::Requires { flag: "--fuzzy", .. })
+        ));
+        assert!(matches!(
+            parse_args(&["--fuzzy", "1", "-e", "to", "-e", "do"]),
+            Err(ArgsError::Requires { flag: "--fuzzy", .. })
+        ));
+        assert!(matches!(parse_args(&["--fuzzy", "2", "ab", "p"]), Err(ArgsError::Requires { flag: "--fuzzy", .. })));
+        assert_eq!(Some(1), parse_args(&["--fuzzy", "1", "ab", "p"]).unwrap().fuzzy);
+        assert!(matches!(parse_args(&["--follow", "to"]), Err(ArgsError::Requires { flag: "--follow", .. })));
+        assert!(matches!(parse_args(&["--follow", "-c", "to", "p"]), Err(ArgsError::Requires { flag: "--follow", .. })));
+        assert!(parse_args(&["--follow", "-n", "to", "p"]).unwrap().follow);
+        assert!(matches!(parse_args(&["--field", "=x", "to"]), Err(ArgsError::InvalidValue { .. })));
+        for rewrites in [&["-o"][..], &["-r", "x"], &["-r", "x", "--dry-run"]] {
+            let args: Vec<_> = ["-U"].iter().chain(rewrites).chain(&["to", "p"]).copied().collect();
+            assert!(matches!(parse_args(&args), Err(ArgsError::Requires { flag: "--multiline", .. })), "{:?}", args);
+        }
+        assert!(matches!(parse_args(&["--field", "a", "-o", "to"]), Err(ArgsError::Requires { flag: "--field", .. })));
+    }
+
+    #[test]
+    fn field_values_can_stand_in_for_the_pattern() {
+        let config = parse_args(&["--field", "level=ERROR", "app.csv"]).unwrap();
+        assert!(config.patterns.is_empty());
+        assert_eq!("app.csv", config.file_path);
+
+        // A plain field still needs a pattern to look for in it
+        let config = parse_args(&["--field", "level=ERROR", "--field", "/user/id", "42", "app.ndjson"]).unwrap();
+        assert_eq!(vec!["42"], config.patterns);
+        assert_eq!("app.ndjson", config.file_path);
+    }
+
+    #[test]
+    fn index_build_is_a_subcommand() {
+        let config = parse_args(&["index", "build", "--hidden"]).unwrap();
+        assert!(config.build_index && config.hidden);
+        assert_eq!(".", config.file_path);
+        assert_eq!("src", parse_args(&["index", "build", "src"]).unwrap().file_path);
+        assert!(matches!(parse_args(&["index", "build", "a", "b"]), Err(ArgsError::UnexpectedArgument(_))));
+
+        // Anywhere else the words are a pattern and a path
+        let config = parse_args(&["index", "built"]).unwrap();
+        assert!(!config.build_index);
+        assert_eq!(vec!["index"], config.patterns);
+        assert!(!parse_args(&["-e", "index", "build"]).unwrap().build_index);
+    }
+
+    #[test]
+    fn rewriting_needs_a_replacement_and_a_path() {
+        let config = parse_args(&["-w", "-r", "$1", "-e", "a", "-e", "b", "--in-place", "p"]).unwrap();
+        assert_eq!(Some("$1"), config.replace.as_deref());
+        assert!(config.regex.is_none());
+        assert!(config.in_place);
+
+        let config = parse_args(&["-E", "--replace=$1", "(a)", "p"]).unwrap();
+        assert_eq!(Some("$1"), config.replace.as_deref());
+
+        assert!(matches!(
+            parse_args(&["--dry-run", "to", "p"]),
+            Err(ArgsError::Requires { flag: "--dry-run", needs: "--replace" })
+        ));
+        assert!(matches!(
+            parse_args(&["-r", "x", "--in-place", "to"]),
+            Err(ArgsError::Requires { flag: "--in-place", needs: "a PATH" })
+        ));
+        for decoded in ["-z", "--encoding=latin1", "--text"] {
+            assert!(matches!(
+                parse_args(&["-r", "x", "--dry-run", decoded, "to", "p"]),
+                Err(ArgsError::Requires { flag: "--dry-run", .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn usage_lists_every_flag() {
+        let text = usage();
+
+        for flag in FLAGS {
+            assert!(text.contains(&format!("--{}", flag.long)));
+        }
+        assert!(text.contains("-A, --after-context NUM"));
+        assert!(text.contains("    --color[=WHEN]"));
+    }
+}
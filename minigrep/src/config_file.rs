@@ -0,0 +1,97 @@
+use std::fs;
+use std::path::Path;
+
+use toml::{Table, Value};
+
+use crate::ArgsError;
+
+// Reads the flags in the MINIGREP_CONFIG file, to be parsed ahead of the
+// command line. Two formats are understood:
+//
+// A plain file holds one argument per line, exactly as it would be typed
+// after minigrep, minus the shell quoting. Blank lines and lines starting
+// with # are skipped:
+//
+//     --smart-case
+//     --glob=!target/**
+//     -j
+//     4
+//
+// A .toml file maps long flag names to values: true for a flag on its own,
+// a string or number for its argument, and an array to repeat the flag:
+//
+//     smart-case = true
+//     glob = ["!target/**", "!*.lock"]
+//     threads = 4
+pub fn args(path: &Path) -> Result<Vec<String>, ArgsError> {
+    let error = |message: String| ArgsError::ConfigFile { path: path.display().to_string(), message };
+
+    let contents = fs::read_to_string(path).map_err(|err| error(err.to_string()))?;
+    if path.extension().is_some_and(|ext| ext == "toml") {
+        from_toml(&contents).map_err(error)
+    } else {
+        Ok(from_lines(&contents))
+    }
+}
+
+fn from_lines(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+fn from_toml(contents: &str) -> Result<Vec<String>, String> {
+    let table: Table = contents.parse().map_err(|err: toml::de::Error| err.message().to_string())?;
+
+    let mut args = Vec::new();
+    for (name, value) in &table {
+        let values = match value {
+            Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                Value::Boolean(true) => args.push(format!("--{}", name)),
+                Value::Boolean(false) => {}
+                Value::String(s) => args.push(format!("--{}={}", name, s)),
+                Value::Integer(n) => args.push(format!("--{}={}", name, n)),
+                _ => return Err(format!("unsupported value for {}: {}", name, value)),
+            }
+        }
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_file_has_one_argument_per_line() {
+        let contents = "# team defaults\n--smart-case\n\n  --glob=!target/**  \n-j\n4\n";
+
+        assert_eq!(vec!["--smart-case", "--glob=!target/**", "-j", "4"], from_lines(contents));
+    }
+
+    #[test]
+    fn toml_keys_are_long_flags() {
+        let contents = "smart-case = true\nhidden = false\nglob = [\"!target/**\", \"*.rs\"]\nthreads = 4\ncolor = \"always\"\n";
+
+        assert_eq!(
+            vec!["--smart-case", "--glob=!target/**", "--glob=*.rs", "--threads=4", "--color=always"],
+            from_toml(contents).unwrap()
+        );
+        assert!(from_toml("context = 1.5").is_err());
+        assert!(from_toml("not toml").is_err());
+    }
+
+    #[test]
+    fn errors_name_the_file() {
+        let err = args(Path::new("/nonexistent/minigrep.toml")).unwrap_err();
+
+        assert!(err.to_string().starts_with("Invalid config file /nonexistent/minigrep.toml: "));
+    }
+}
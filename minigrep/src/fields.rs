@@ -0,0 +1,255 @@
+use serde_json::Value;
+
+use crate::{Config, Matcher};
+
+// One --field: where in a record to look, and with NAME=VALUE, what has to
+// be there. name is a CSV column, or for JSON Lines a key or a JSON pointer
+// (/user/id).
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Field {
+    name: String,
+    value: Option<String>,
+}
+
+impl Field {
+    fn parse(spec: &str) -> Field {
+        match spec.split_once('=') {
+            Some((name, value)) => Field { name: name.to_string(), value: Some(value.to_string()) },
+            None => Field { name: spec.to_string(), value: None },
+        }
+    }
+}
+
+// Whether spec can be a --field: it needs something to name
+pub fn check(spec: &str) -> bool {
+    !Field::parse(spec).name.is_empty()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    JsonLines,
+    // Separated values, split on the character given; the first line names
+    // the columns
+    Csv(char),
+}
+
+// --field: searches the lines of one input as records, CSV or JSON Lines,
+// instead of as text. Which of the two is decided by the first line that
+// isn't blank: JSON Lines if it starts with {, and CSV otherwise (TSV if
+// it has tabs and no commas), with that line as the header.
+//
+// A record is selected when every NAME=VALUE field is VALUE (ignoring case
+// with -i), and the patterns match in one of the plain NAME fields, or
+// anywhere in the line if there are none. With only NAME=VALUE fields and
+// no patterns (see cli::finish), the values alone decide.
+pub struct Records {
+    fields: Vec<Field>,
+    ignore_case: bool,
+    no_patterns: bool,
+    format: Option<Format>,
+    columns: Vec<String>,
+}
+
+impl Records {
+    pub fn new(config: &Config) -> Records {
+        let fields = config.fields.iter().map(|spec| Field::parse(spec)).collect();
+        Records {
+            fields,
+            ignore_case: config.ignore_case,
+            no_patterns: config.patterns.is_empty(),
+            format: None,
+            columns: Vec::new(),
+        }
+    }
+
+    // Whether line, a record, is selected; None if it isn't a record at all
+    // (the header, a blank line, or a line that doesn't parse), which -v
+    // doesn't select either
+    pub fn select(&mut self, matcher: &Matcher, line: &str) -> Option<bool> {
+        if line.trim().is_empty() {
+            return None;
+        }
+        let format = match self.format {
+            Some(format) => format,
+            None => {
+                let format = if line.trim_start().starts_with('{') {
+                    Format::JsonLines
+                } else if line.contains('\t') && !line.contains(',') {
+                    Format::Csv('\t')
+                } else {
+                    Format::Csv(',')
+                };
+                self.format = Some(format);
+                if let Format::Csv(delimiter) = format {
+                    self.columns = split(line, delimiter)?;
+                    return None;
+                }
+                format
+            }
+        };
+
+        let values: Vec<Option<String>> = match format {
+            Format::JsonLines => {
+                let record: Value = serde_json::from_str(line).ok()?;
+                self.fields.iter().map(|field| json_field(&record, &field.name)).collect()
+            }
+            Format::Csv(delimiter) => {
+                let record = split(line, delimiter)?;
+                self.fields
+                    .iter()
+                    .map(|field| {
+                        let name = field.name.strip_prefix('/').filter(|n| !n.contains('/')).unwrap_or(&field.name);
+                        let i = self.columns.iter().position(|column| column == name)?;
+                        record.get(i).cloned()
+                    })
+                    .collect()
+            }
+        };
+
+        let mut searched = Vec::new();
+        for (field, found) in self.fields.iter().zip(&values) {
+            match (&field.value, found) {
+                (Some(wanted), Some(found)) if self.same(wanted, found) => {}
+                (Some(_), _) => return Some(false),
+                (None, Some(found)) => searched.push(found.as_str()),
+                (None, None) => {}
+            }
+        }
+
+        // NAME=VALUE fields can stand in for the patterns
+        if self.no_patterns && self.fields.iter().any(|field| field.value.is_some()) {
+            return Some(true);
+        }
+        if self.fields.iter().all(|field| field.value.is_some()) {
+            return Some(matcher.is_match(line));
+        }
+        Some(searched.iter().any(|value| matcher.is_match(value)))
+    }
+
+    fn same(&self, wanted: &str, found: &str) -> bool {
+        if self.ignore_case {
+            caseless::default_caseless_match_str(wanted, found)
+        } else {
+            wanted == found
+        }
+    }
+}
+
+// The fields of one line of CSV, or None if it doesn't parse. A quoted
+// field can hold the delimiter, and "" for a quote, but not a line break:
+// every record is one line, so a field that goes on to the next one leaves
+// both lines unparsed.
+fn split(line: &str, delimiter: char) -> Option<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        let mut field = String::new();
+        let end = if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next()? {
+                    '"' if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    '"' => break,
+                    c => field.push(c),
+                }
+            }
+            // Nothing but a delimiter may follow the closing quote
+            match chars.next() {
+                Some(c) if c != delimiter => return None,
+                next => next.is_none(),
+            }
+        } else {
+            loop {
+                match chars.next() {
+                    Some(c) if c == delimiter => break false,
+                    Some(c) => field.push(c),
+                    None => break true,
+                }
+            }
+        };
+        fields.push(field);
+        if end {
+            return Some(fields);
+        }
+    }
+}
+
+// The field of record that name points to, as text: strings as they are,
+// anything else as JSON
+fn json_field(record: &Value, name: &str) -> Option<String> {
+    let found = if name.starts_with('/') {
+        record.pointer(name)
+    } else {
+        record.get(name)
+    }?;
+    Some(match found {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_args;
+
+    fn selected(flags: &[&str], contents: &str) -> Vec<Option<bool>> {
+        let config = parse_args(flags).unwrap();
+        let matcher = Matcher::new(&config).unwrap();
+        let mut records = Records::new(&config);
+        contents.lines().map(|line| records.select(&matcher, line)).collect()
+    }
+
+    #[test]
+    fn csv_columns_by_name() {
+        let csv = "level,message,user\nERROR,disk full,ann\nINFO,\"full, but fine\",bob\nerror,disk full,cy\n";
+
+        assert_eq!(
+            vec![None, Some(true), Some(false), Some(false)],
+            selected(&["--field", "level=ERROR", "-"], csv)
+        );
+        assert_eq!(
+            vec![None, Some(true), Some(false), Some(true)],
+            selected(&["-i", "--field", "level=ERROR", "-e", "disk"], csv)
+        );
+        // A pattern is only looked for in the plain fields
+        assert_eq!(
+            vec![None, Some(false), Some(true), Some(false)],
+            selected(&["--field", "user", "b"], csv)
+        );
+        assert_eq!(vec![None, Some(true), Some(false)], selected(&["--field", "/a", "1"], "a\tb\n1\t2\n2\t1\n"));
+    }
+
+    #[test]
+    fn csv_lines_split_on_unquoted_delimiters() {
+        let fields = |line| split(line, ',');
+
+        assert_eq!(Some(vec!["a".to_string(), "".to_string(), "c".to_string()]), fields("a,,c"));
+        assert_eq!(Some(vec!["x, \"y\"".to_string(), "z".to_string()]), fields("\"x, \"\"y\"\"\",z"));
+        assert_eq!(Some(vec!["".to_string()]), fields(""));
+        // A quoted field that would go on to the next line
+        assert_eq!(None, fields("a,\"two"));
+        assert_eq!(None, fields("\"a\"b,c"));
+    }
+
+    #[test]
+    fn json_lines_by_key_and_pointer() {
+        let ndjson = r#"{"level":"ERROR","user":{"id":42}}
+{"level":"INFO","user":{"id":7},"note":"42"}
+not json
+{"level":"ERROR","user":{"id":7}}
+"#;
+
+        assert_eq!(
+            vec![Some(true), Some(false), None, Some(false)],
+            selected(&["--field", "level=ERROR", "--field", "/user/id", "42"], ndjson)
+        );
+        assert_eq!(
+            vec![Some(false), Some(true), None, Some(true)],
+            selected(&["--field", "/user/id=7"], ndjson)
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use std::iter;
+use std::ops::Range;
+
+use caseless::Caseless;
+
+// A literal that matches regardless of case, by comparing Unicode full
+// case folds instead of lowercased copies. Folding maps every case variant
+// of a character to the same sequence: "ß", "ẞ" and "SS" all fold to "ss",
+// and "Σ", "σ" and "ς" all fold to "σ". Lines are folded a character at a
+// time while they are compared, so nothing is allocated per line.
+#[derive(Debug, Clone)]
+pub struct FoldedLiteral {
+    needle: Vec<char>,
+}
+
+impl FoldedLiteral {
+    pub fn new(query: &str) -> FoldedLiteral {
+        FoldedLiteral { needle: query.chars().default_case_fold().collect() }
+    }
+
+    pub fn is_match(&self, haystack: &str) -> bool {
+        self.find_at(haystack, 0).is_some()
+    }
+
+    // The byte range of the first match that starts at or after start,
+    // which must be on a char boundary
+    pub fn find_at(&self, haystack: &str, start: usize) -> Option<Range<usize>> {
+        let first = match self.needle.first() {
+            Some(&first) => first,
+            None => return Some(start..start),
+        };
+
+        haystack[start..].char_indices().find_map(|(i, c)| {
+            // Cheap check on the first folded char before comparing the rest
+            if iter::once(c).default_case_fold().next() != Some(first) {
+                return None;
+            }
+            let from = start + i;
+            self.match_len(&haystack[from..]).map(|len| from..from + len)
+        })
+    }
+
+    // The byte ranges of every non-overlapping, non-empty match
+    pub fn find_iter<'a>(&'a self, haystack: &'a str) -> impl Iterator<Item = Range<usize>> + 'a {
+        let mut start = 0;
+        iter::from_fn(move || {
+            if self.needle.is_empty() {
+                return None;
+            }
+            let found = self.find_at(haystack, start)?;
+            start = found.end;
+            Some(found)
+        })
+    }
+
+    // How many bytes at the start of s fold to exactly the needle. A match
+    // has to end on a character boundary of s: "s" doesn't match half of "ß".
+    fn match_len(&self, s: &str) -> Option<usize> {
+        let mut matched = 0;
+        for (i, c) in s.char_indices() {
+            for folded in iter::once(c).default_case_fold() {
+                if self.needle.get(matched) != Some(&folded) {
+                    return None;
+                }
+                matched += 1;
+            }
+            if matched == self.needle.len() {
+                return Some(i + c.len_utf8());
+            }
+        }
+        None
+    }
+}
+
+// A pattern folded the way FoldedLiteral folds lines, to search folded text
+// for
+pub fn fold(pattern: &str) -> String {
+    pattern.chars().default_case_fold().collect()
+}
+
+// A whole line folded at once, for searching with an automaton over folded
+// patterns, along with the way back to the line. starts[i] is where in the
+// line the character whose fold begins at byte i of text came from, or None
+// when i is partway through one ("ß" folds to two bytes, "ss"). ASCII lines
+// fold byte for byte and don't need it.
+pub struct Folded {
+    pub text: String,
+    starts: Option<Vec<Option<usize>>>,
+}
+
+impl Folded {
+    pub fn new(line: &str) -> Folded {
+        if line.is_ascii() {
+            return Folded { text: line.to_ascii_lowercase(), starts: None };
+        }
+        let mut text = String::with_capacity(line.len());
+        let mut starts = Vec::with_capacity(line.len() + 1);
+        for (i, c) in line.char_indices() {
+            text.extend(iter::once(c).default_case_fold());
+            starts.push(Some(i));
+            starts.resize(text.len(), None);
+        }
+        starts.push(Some(line.len()));
+        Folded { text, starts: Some(starts) }
+    }
+
+    // The part of the line that range of text is the fold of, if it starts
+    // and ends on the fold of a whole character: "s" doesn't match half of
+    // "ß"
+    pub fn original(&self, range: Range<usize>) -> Option<Range<usize>> {
+        match &self.starts {
+            None => Some(range),
+            Some(starts) => Some(starts[range.start]?..starts[range.end]?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_full_case_folds() {
+        assert!(FoldedLiteral::new("STRASSE").is_match("Die Straße"));
+        assert!(FoldedLiteral::new("straße").is_match("STRASSE"));
+        assert!(FoldedLiteral::new("ΣΟΦΟΣ").is_match("σοφος"));
+        assert!(FoldedLiteral::new("i\u{307}stanbul").is_match("İstanbul"));
+        assert!(!FoldedLiteral::new("s").is_match("ß"));
+    }
+
+    #[test]
+    fn ranges_point_into_the_original_line() {
+        let folded = FoldedLiteral::new("ss");
+
+        assert_eq!(
+            vec![4..6, 11..13],
+            folded.find_iter("Stra\u{df}e, Mass").collect::<Vec<_>>()
+        );
+        assert_eq!(0, FoldedLiteral::new("").find_iter("abc").count());
+    }
+
+    #[test]
+    fn folded_lines_map_back_to_whole_characters() {
+        // Capital sharp s is three bytes, and its fold two
+        let line = Folded::new("Stra\u{1e9e}e, Mass");
+
+        assert_eq!("strasse, mass", line.text);
+        assert_eq!(Some(4..7), line.original(4..6));
+        assert_eq!(None, line.original(4..5));
+        assert_eq!(Some(10..14), line.original(9..13));
+        assert_eq!(Some(0..3), Folded::new("ABC").original(0..3));
+        assert_eq!("ss", fold("SS"));
+    }
+}
@@ -0,0 +1,440 @@
+use std::fs::{self, File, Metadata};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use memchr::{memchr, memchr_iter, memrchr_iter};
+
+use crate::fields::Records;
+use crate::printer::Printer;
+use crate::{input, sink, Config, Line, Matcher, MaxCount};
+
+// How many of the lines already in the file are searched before waiting
+// for new ones, as tail -f prints
+const TAIL_LINES: usize = 10;
+
+// How much of the file is read at once while looking for the tail
+const BLOCK_SIZE: usize = 64 * 1024;
+
+// How often the file is looked at when there is no inotify
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// How long to wait for inotify before looking at the file anyway, in case
+// a change wasn't reported (files on network file systems, say)
+const WATCH_TIMEOUT: Duration = Duration::from_secs(1);
+
+// --follow: searches the end of the file at path, then keeps it open and
+// searches lines as they are appended, like tail -f | grep. When the file
+// is truncated it's read again from the start; when another file takes its
+// name (log rotation), the rest of the old one is read and then the new
+// one is followed. Only returns on an error, or once --max-count lines have
+// been selected.
+pub fn file<W: Write>(config: &Config, matcher: &Matcher, printer: &mut Printer<W>, path: &Path) -> io::Result<()> {
+    follow(config, matcher, printer, path, &mut Watcher::new(path))
+}
+
+fn follow<W: Write>(
+    config: &Config,
+    matcher: &Matcher,
+    printer: &mut Printer<W>,
+    path: &Path,
+    watcher: &mut Watcher,
+) -> io::Result<()> {
+    // --field reads each file as records, starting with its header
+    let new_records = || (!config.fields.is_empty()).then(|| Records::new(config));
+    let mut records = new_records();
+
+    let mut followed = Followed::open(path)?;
+    // Only -n and --json show line numbers
+    let skipped_first = followed.skip_to_tail(TAIL_LINES, config.line_number || config.json)?;
+    if let (Some(records), Some(first)) = (&mut records, skipped_first) {
+        records.select(matcher, &first);
+    }
+
+    let mut sink = sink::for_config(config, matcher, printer, path, false, false);
+    let mut limit = MaxCount::new(config);
+    let mut take = |m: &Line, records: &mut Option<Records>| {
+        let selected = match records {
+            Some(records) => records.select(matcher, m.line).is_some_and(|found| found != config.invert_match),
+            None => matcher.is_match(m.line) != config.invert_match,
+        };
+        limit.take(sink.as_mut(), m, selected, None)
+    };
+
+    loop {
+        if !followed.read_lines(&mut |m| take(m, &mut records))? {
+            break;
+        }
+
+        match followed.change(path)? {
+            Change::None => watcher.wait(),
+            Change::Truncated => {
+                eprintln!("minigrep: {}: file truncated", path.display());
+                followed.rewind()?;
+                records = new_records();
+            }
+            Change::Replaced => {
+                // Whatever was written to the old file before it was
+                // replaced is still searched, unfinished last line included
+                if !followed.read_lines(&mut |m| take(m, &mut records))?
+                    || !followed.end_line(&mut |m| take(m, &mut records))?
+                {
+                    break;
+                }
+                match Followed::open(path) {
+                    Ok(replacement) => {
+                        eprintln!("minigrep: {} has been replaced; following the new file", path.display());
+                        followed = replacement;
+                        records = new_records();
+                    }
+                    // Gone again already; wait for the next one
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => watcher.wait(),
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+    }
+
+    sink.finish()
+}
+
+// What happened to a followed file since it was last read to its end
+enum Change {
+    None,
+    Truncated,
+    Replaced,
+}
+
+// The file being followed, and how far into it lines have been searched
+struct Followed {
+    file: File,
+    id: Option<FileId>,
+    // How many bytes have been read, which is where the next read starts
+    read: u64,
+    // The number of the last line searched, and the offset of the next
+    line_number: usize,
+    byte_offset: usize,
+    // The start of a line that hasn't been finished yet
+    pending: Vec<u8>,
+}
+
+impl Followed {
+    fn open(path: &Path) -> io::Result<Followed> {
+        let file = File::open(path)?;
+        let id = file_id(&file.metadata()?);
+        Ok(Followed { file, id, read: 0, line_number: 0, byte_offset: 0, pending: Vec::new() })
+    }
+
+    // Moves on to the last `lines` complete lines, found by reading back
+    // from the end of the file a block at a time, so a long log isn't read
+    // through first. The lines skipped are only counted, a block at a time
+    // too, with count_lines: otherwise no line numbers are printed, and
+    // they start again from the tail. Returns the first line if it was
+    // skipped, since a CSV file's header is still needed.
+    fn skip_to_tail(&mut self, lines: usize, count_lines: bool) -> io::Result<Option<String>> {
+        let mut buf = vec![0; BLOCK_SIZE];
+
+        // The tail starts after the newline before its first line, the
+        // (lines + 1)th from the end, or at the start of the file
+        let mut start = 0;
+        let mut seen = 0;
+        let mut end = self.file.metadata()?.len();
+        'blocks: while end > 0 {
+            let from = end.saturating_sub(BLOCK_SIZE as u64);
+            let block = &mut buf[..(end - from) as usize];
+            self.file.seek(SeekFrom::Start(from))?;
+            self.file.read_exact(block)?;
+            for i in memrchr_iter(b'\n', block) {
+                seen += 1;
+                if seen == lines + 1 {
+                    start = from + i as u64 + 1;
+                    break 'blocks;
+                }
+            }
+            end = from;
+        }
+
+        let mut first = None;
+        let mut skipped = 0;
+        if start > 0 {
+            self.file.seek(SeekFrom::Start(0))?;
+            let mut line = Vec::new();
+            BufReader::new(&self.file).take(start).read_until(b'\n', &mut line)?;
+            let text = line.strip_suffix(b"\n").unwrap_or(&line);
+            let text = text.strip_suffix(b"\r").unwrap_or(text);
+            first = Some(String::from_utf8_lossy(text).into_owned());
+
+            if count_lines {
+                self.file.seek(SeekFrom::Start(0))?;
+                let mut left = start;
+                while left > 0 {
+                    let block = &mut buf[..left.min(BLOCK_SIZE as u64) as usize];
+                    self.file.read_exact(block)?;
+                    skipped += memchr_iter(b'\n', block).count();
+                    left -= block.len() as u64;
+                }
+            }
+        }
+
+        self.file.seek(SeekFrom::Start(start))?;
+        self.read = start;
+        self.line_number = skipped;
+        self.byte_offset = start as usize;
+        Ok(first)
+    }
+
+    // Passes f each line that has been finished since the last call, until
+    // the end of the file or f says to stop; returns whether to go on
+    fn read_lines(&mut self, f: &mut impl FnMut(&Line) -> io::Result<bool>) -> io::Result<bool> {
+        let mut buf = [0; 64 * 1024];
+        loop {
+            let read = self.file.read(&mut buf)?;
+            if read == 0 {
+                return Ok(true);
+            }
+            self.read += read as u64;
+            self.pending.extend_from_slice(&buf[..read]);
+
+            let mut start = 0;
+            while let Some(i) = memchr(b'\n', &self.pending[start..]) {
+                let end = start + i + 1;
+                self.line_number += 1;
+                let more = input::emit(f, self.line_number, self.byte_offset, &self.pending[start..end])?;
+                self.byte_offset += end - start;
+                start = end;
+                if !more {
+                    self.pending.drain(..start);
+                    return Ok(false);
+                }
+            }
+            self.pending.drain(..start);
+        }
+    }
+
+    // Passes f the unfinished last line, if there is one, as it is
+    fn end_line(&mut self, f: &mut impl FnMut(&Line) -> io::Result<bool>) -> io::Result<bool> {
+        if self.pending.is_empty() {
+            return Ok(true);
+        }
+        self.line_number += 1;
+        let line = std::mem::take(&mut self.pending);
+        input::emit(f, self.line_number, self.byte_offset, &line)
+    }
+
+    fn change(&self, path: &Path) -> io::Result<Change> {
+        if self.file.metadata()?.len() < self.read {
+            return Ok(Change::Truncated);
+        }
+        // While nothing has the name, the old file is kept
+        Ok(match fs::metadata(path) {
+            Ok(metadata) if self.id.is_some() && file_id(&metadata) != self.id => Change::Replaced,
+            _ => Change::None,
+        })
+    }
+
+    // Starts over from the beginning of the file, after it was truncated
+    fn rewind(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.read = 0;
+        self.line_number = 0;
+        self.byte_offset = 0;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+// What tells one file from another that took its name: its device and
+// inode. Where there is no such thing, rotation goes unnoticed.
+type FileId = (u64, u64);
+
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<FileId> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_: &Metadata) -> Option<FileId> {
+    None
+}
+
+// Waits for the followed file to (maybe) have changed
+enum Watcher {
+    #[cfg(target_os = "linux")]
+    Inotify(std::os::fd::OwnedFd),
+    Poll,
+}
+
+impl Watcher {
+    // Watches with inotify where it's available, and polls otherwise
+    fn new(path: &Path) -> Watcher {
+        #[cfg(target_os = "linux")]
+        if let Some(fd) = inotify::watch(path) {
+            return Watcher::Inotify(fd);
+        }
+        let _ = path;
+        Watcher::Poll
+    }
+
+    fn wait(&mut self) {
+        match self {
+            #[cfg(target_os = "linux")]
+            Watcher::Inotify(fd) => inotify::wait(fd, WATCH_TIMEOUT),
+            Watcher::Poll => thread::sleep(POLL_INTERVAL),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use std::ffi::CString;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+    use std::time::Duration;
+
+    // Watches the directory path is in rather than the file itself, so a
+    // new file taking its name is heard about too
+    pub fn watch(path: &Path) -> Option<OwnedFd> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let dir = CString::new(dir.as_os_str().as_bytes()).ok()?;
+        let mask = libc::IN_MODIFY
+            | libc::IN_ATTRIB
+            | libc::IN_CLOSE_WRITE
+            | libc::IN_CREATE
+            | libc::IN_DELETE
+            | libc::IN_MOVED_FROM
+            | libc::IN_MOVED_TO;
+
+        // SAFETY: plain libc calls; the descriptor is owned as soon as it
+        // has been created
+        unsafe {
+            let fd = libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK);
+            if fd < 0 {
+                return None;
+            }
+            let fd = OwnedFd::from_raw_fd(fd);
+            if libc::inotify_add_watch(fd.as_raw_fd(), dir.as_ptr(), mask) < 0 {
+                return None;
+            }
+            Some(fd)
+        }
+    }
+
+    // Waits until something happens in the directory or timeout passes.
+    // Which event it was doesn't matter, so they are all thrown away.
+    pub fn wait(fd: &OwnedFd, timeout: Duration) {
+        let mut pollfd = libc::pollfd { fd: fd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        let mut events = [0u8; 4096];
+        // SAFETY: pollfd and events outlive the calls that are given them
+        unsafe {
+            libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int);
+            while libc::read(fd.as_raw_fd(), events.as_mut_ptr().cast(), events.len()) > 0 {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_args;
+    use std::fs::OpenOptions;
+
+    fn append(path: &Path, text: &str) {
+        OpenOptions::new().append(true).open(path).unwrap().write_all(text.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn the_tail_is_found_from_the_end_of_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("big.log");
+        let text: String = (1..=20_000).map(|i| format!("line {}\r\n", i)).collect();
+        fs::write(&path, &text).unwrap();
+        let tail = text.rfind("line 19991\r\n").unwrap();
+
+        let mut followed = Followed::open(&path).unwrap();
+        assert_eq!(Some("line 1".to_string()), followed.skip_to_tail(10, true).unwrap());
+        assert_eq!((tail as u64, 19_990), (followed.read, followed.line_number));
+        assert_eq!(tail, followed.byte_offset);
+
+        let mut followed = Followed::open(&path).unwrap();
+        followed.skip_to_tail(10, false).unwrap();
+        assert_eq!((tail as u64, 0), (followed.read, followed.line_number));
+
+        let mut followed = Followed::open(&path).unwrap();
+        assert_eq!(None, followed.skip_to_tail(20_000, true).unwrap());
+        assert_eq!((0, 0), (followed.read, followed.line_number));
+    }
+
+    #[test]
+    fn follows_appends_truncation_and_rotation() {
+        for inotify in [false, true] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("app.log");
+            let old: String = (1..=12).map(|i| format!("{} {}\n", if i % 11 < 3 { "hit" } else { "miss" }, i)).collect();
+            fs::write(&path, old).unwrap();
+            let mut watcher = if inotify { Watcher::new(&path) } else { Watcher::Poll };
+
+            let writer = {
+                let path = path.clone();
+                thread::spawn(move || {
+                    let pause = || thread::sleep(Duration::from_millis(400));
+                    pause();
+                    append(&path, "hit 13\nmiss 14\nhi");
+                    pause();
+                    append(&path, "t 15\n");
+                    pause();
+                    OpenOptions::new().write(true).open(&path).unwrap().set_len(0).unwrap();
+                    pause();
+                    append(&path, "hit again\n");
+                    pause();
+                    fs::rename(&path, path.with_extension("log.1")).unwrap();
+                    pause();
+                    fs::write(&path, "miss\nhit rotated\n").unwrap();
+                })
+            };
+
+            let config = parse_args(&["-n", "-m", "6", "--follow", "hit", "app.log"]).unwrap();
+            let matcher = Matcher::new(&config).unwrap();
+            let mut out = Vec::new();
+            let mut printer = Printer::new(&mut out, &config);
+            follow(&config, &matcher, &mut printer, &path, &mut watcher).unwrap();
+            writer.join().unwrap();
+
+            // Of the lines already there, only the last ten are searched
+            assert_eq!(
+                "11:hit 11\n12:hit 12\n13:hit 13\n15:hit 15\n1:hit again\n2:hit rotated\n",
+                String::from_utf8(out).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn fields_are_searched_with_the_header_of_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.csv");
+        let rows: String = (2..=12).map(|i| format!("INFO,{}\n", i)).collect();
+        fs::write(&path, format!("level,message\n{}ERROR,old\n", rows)).unwrap();
+
+        let writer = {
+            let path = path.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(400));
+                append(&path, "INFO,ERROR\nERROR,disk\n");
+            })
+        };
+
+        let config = parse_args(&["-n", "-m", "2", "--follow", "--field", "level=ERROR", "app.csv"]).unwrap();
+        let matcher = Matcher::new(&config).unwrap();
+        let mut out = Vec::new();
+        let mut printer = Printer::new(&mut out, &config);
+        follow(&config, &matcher, &mut printer, &path, &mut Watcher::Poll).unwrap();
+        writer.join().unwrap();
+
+        assert_eq!("13:ERROR,old\n15:ERROR,disk\n", String::from_utf8(out).unwrap());
+    }
+}
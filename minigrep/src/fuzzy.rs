@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+// The longest query --fuzzy takes: bitap keeps one bit per query character
+// in a u64
+pub const MAX_LEN: usize = 64;
+
+// A query that also matches text up to max_edits insertions, deletions or
+// substitutions of a character away from it (--fuzzy K), so "recieve"
+// finds "receive". Lines are tested with bitap (Wu and Manber's extension
+// of shift-and to k errors), which keeps one bitmask per allowed number of
+// edits and does a handful of bit operations per character of the line.
+#[derive(Debug, Clone)]
+pub struct Fuzzy {
+    needle: Vec<char>,
+    // For each character of the needle, the positions it appears at
+    masks: HashMap<char, u64>,
+    max_edits: usize,
+    ignore_case: bool,
+}
+
+impl Fuzzy {
+    // None if query is longer than MAX_LEN characters
+    pub fn new(query: &str, max_edits: usize, ignore_case: bool) -> Option<Fuzzy> {
+        let needle: Vec<char> = query.chars().map(|c| fold(c, ignore_case)).collect();
+        if needle.len() > MAX_LEN {
+            return None;
+        }
+
+        let mut masks = HashMap::new();
+        for (i, &c) in needle.iter().enumerate() {
+            *masks.entry(c).or_insert(0) |= 1 << i;
+        }
+        Some(Fuzzy { needle, masks, max_edits, ignore_case })
+    }
+
+    // The fewest edits that turn some part of line into the query, if that
+    // is no more than max_edits
+    pub fn distance(&self, line: &str) -> Option<usize> {
+        let m = self.needle.len();
+        if m == 0 {
+            return Some(0);
+        }
+
+        let last = 1u64 << (m - 1);
+        // Text m edits away needn't share a character with the query, so
+        // like find_at, only fewer than that count as a match
+        let max_edits = self.max_edits.min(m - 1);
+        // state[d] has bit i set when the first i + 1 characters of the
+        // query match, with at most d edits, text ending at the current
+        // character. Up front, with no text read, d deletions are free.
+        let mut state: Vec<u64> = (0..=max_edits)
+            .map(|d| 1u64.checked_shl(d as u32).map_or(u64::MAX, |bit| bit - 1))
+            .collect();
+        let mut best = state.iter().position(|s| s & last != 0);
+
+        for c in line.chars() {
+            if best == Some(0) {
+                break;
+            }
+            let mask = self.masks.get(&fold(c, self.ignore_case)).copied().unwrap_or(0);
+            let mut prev_old = state[0];
+            state[0] = ((state[0] << 1) | 1) & mask;
+            for d in 1..state.len() {
+                let old = state[d];
+                state[d] = (((old << 1) | 1) & mask)
+                    // substitute c for the next query character
+                    | (prev_old << 1) | 1
+                    // skip c, an inserted character
+                    | prev_old
+                    // skip the next query character, a deleted one
+                    | (state[d - 1] << 1) | 1;
+                prev_old = old;
+            }
+
+            if let Some(d) = state.iter().position(|s| s & last != 0) {
+                best = Some(best.map_or(d, |b| b.min(d)));
+            }
+        }
+        best
+    }
+
+    pub fn is_match(&self, line: &str) -> bool {
+        self.distance(line).is_some()
+    }
+
+    // The byte ranges of non-overlapping matches within max_edits, from
+    // left to right. Each one starts out as the earliest text within
+    // max_edits of the query, and is extended for as long as that brings
+    // it closer. A match has to keep at least one character of the query,
+    // so a short query with a large K doesn't highlight everything.
+    pub fn find_iter(&self, line: &str) -> Vec<Range<usize>> {
+        let mut found = Vec::new();
+        let mut start = 0;
+        while let Some(range) = self.find_at(line, start) {
+            start = range.end;
+            found.push(range);
+        }
+        found
+    }
+
+    // The first match at or after byte start, which must be on a char
+    // boundary. Worked out with the edit distance table (Sellers'
+    // algorithm), which unlike bitap can tell where each match starts; it
+    // only runs on lines that matched.
+    pub fn find_at(&self, line: &str, start: usize) -> Option<Range<usize>> {
+        let m = self.needle.len();
+        // cost[j] is the distance between the first j characters of the
+        // query and the closest text ending here, which starts at from[j]
+        let mut cost: Vec<usize> = (0..=m).collect();
+        let mut from = vec![start; m + 1];
+        let mut best: Option<(Range<usize>, usize)> = None;
+
+        for (i, c) in line[start..].char_indices() {
+            let end = start + i + c.len_utf8();
+            let c = fold(c, self.ignore_case);
+
+            // The text can start anywhere for free
+            let (mut diagonal, mut diagonal_from) = (cost[0], from[0]);
+            from[0] = end;
+            for j in 1..=m {
+                let (above, above_from) = (cost[j], from[j]);
+                let (mut next, mut next_from) = (diagonal + usize::from(self.needle[j - 1] != c), diagonal_from);
+                if above + 1 < next {
+                    (next, next_from) = (above + 1, above_from);
+                }
+                if cost[j - 1] + 1 < next {
+                    (next, next_from) = (cost[j - 1] + 1, from[j - 1]);
+                }
+                (diagonal, diagonal_from) = (above, above_from);
+                cost[j] = next;
+                from[j] = next_from;
+            }
+
+            let distance = cost[m];
+            match &best {
+                Some((_, best_distance)) if distance < *best_distance => best = Some((from[m]..end, distance)),
+                Some(_) => break,
+                None if distance <= self.max_edits && distance < m => best = Some((from[m]..end, distance)),
+                None => {}
+            }
+        }
+        best.map(|(range, _)| range)
+    }
+}
+
+fn fold(c: char, ignore_case: bool) -> char {
+    if ignore_case {
+        c.to_lowercase().next().unwrap_or(c)
+    } else {
+        c
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distance_counts_edits() {
+        let query = Fuzzy::new("receive", 2, false).unwrap();
+
+        assert_eq!(Some(0), query.distance("we receive mail"));
+        assert_eq!(Some(1), query.distance("we receve mail"));
+        assert_eq!(Some(1), query.distance("we receivve mail"));
+        // Swapped letters are two substitutions
+        assert_eq!(Some(2), query.distance("we recieve mail"));
+        assert_eq!(None, query.distance("we rcieve mail"));
+        assert_eq!(None, Fuzzy::new("receive", 1, false).unwrap().distance("we recieve mail"));
+
+        assert_eq!(Some(0), Fuzzy::new("RECEIVE", 0, true).unwrap().distance("Receive"));
+        assert_eq!(Some(1), Fuzzy::new("ab", 2, false).unwrap().distance("xb"));
+        assert_eq!(None, Fuzzy::new("ab", 2, false).unwrap().distance(""));
+        assert_eq!(None, Fuzzy::new("ab", 2, false).unwrap().distance("xy"));
+        assert_eq!(Some(0), Fuzzy::new("", 2, false).unwrap().distance("anything"));
+    }
+
+    #[test]
+    fn ranges_cover_the_closest_text() {
+        let query = Fuzzy::new("recieve", 2, false).unwrap();
+
+        assert_eq!(vec![2..9], query.find_iter("I received it"));
+        assert_eq!(vec![0..7, 8..14], query.find_iter("recieve reciev x"));
+        assert_eq!(vec![3..9], Fuzzy::new("naïve", 0, true).unwrap().find_iter("so NAÏVE"));
+        assert!(query.find_iter("nothing here").is_empty());
+        assert!(Fuzzy::new("ab", 5, false).unwrap().find_iter("xyz").is_empty());
+    }
+
+    #[test]
+    fn queries_longer_than_max_len_are_refused() {
+        assert!(Fuzzy::new(&"é".repeat(MAX_LEN), 1, true).is_some());
+        assert!(Fuzzy::new(&"x".repeat(MAX_LEN + 1), 1, false).is_none());
+    }
+}
@@ -0,0 +1,375 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, Metadata};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use tempfile::NamedTempFile;
+
+use crate::input::{Input, MmapChoice};
+use crate::{walk, Config};
+
+// Where minigrep index build DIR writes the index, and --index looks for it
+pub const FILE_NAME: &str = ".minigrep-index";
+
+const MAGIC: &[u8] = b"minigrep-index 1\n";
+
+// A trigram index of the files under a directory (minigrep index build),
+// so that repeated searches with --index only read the files that can
+// possibly match.
+//
+// For every file it holds the set of three-byte sequences that occur in
+// the file's text, with ASCII letters lowercased. A literal pattern can
+// only occur in files that have every trigram of the pattern, which rules
+// out most files for most queries; the files that are left are searched
+// as usual, so the index never changes what is found, only how much is
+// read. Files whose size or modification time don't match the index are
+// always searched, so an out-of-date index is slower but never wrong.
+//
+// On disk it's MAGIC followed by one record per file: the path relative
+// to the directory, the modification time (seconds, nanoseconds) and
+// size, and the sorted trigrams as differences from the previous one.
+// Every number is a LEB128 varint, and the path is prefixed by its length.
+#[derive(Debug, Default)]
+pub struct Index {
+    files: HashMap<String, Entry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    modified: (u64, u32),
+    size: u64,
+    trigrams: Vec<u32>,
+}
+
+// What index build did
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct BuildStats {
+    pub files: usize,
+    // Files that were new or had changed since the last build
+    pub read: usize,
+}
+
+// minigrep index build: indexes the files under root that a search of root
+// would look at, so the same --hidden, --no-ignore, --glob and -t flags
+// apply. Files that haven't changed since the previous index was built are
+// taken from it rather than read again.
+pub fn build(root: &Path, config: &Config) -> Result<BuildStats, Box<dyn Error>> {
+    let index_path = root.join(FILE_NAME);
+    // A missing or unreadable old index only means starting from scratch
+    let old = Index::load(&index_path).unwrap_or_default();
+
+    let mut index = Index::default();
+    let mut stats = BuildStats::default();
+    for path in walk::files(root, config)? {
+        let Some(name) = relative_name(root, &path) else { continue };
+        if name == FILE_NAME {
+            continue;
+        }
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                eprintln!("minigrep: {}: {}", path.display(), err);
+                continue;
+            }
+        };
+
+        let entry = match old.files.get(&name) {
+            Some(entry) if entry.is_current(&metadata) => entry.clone(),
+            _ => match read_entry(&path, &metadata) {
+                Ok(entry) => {
+                    stats.read += 1;
+                    entry
+                }
+                Err(err) => {
+                    eprintln!("minigrep: {}: {}", path.display(), err);
+                    continue;
+                }
+            },
+        };
+        index.files.insert(name, entry);
+        stats.files += 1;
+    }
+
+    index.save(&index_path)?;
+    Ok(stats)
+}
+
+// --index: the files, out of the ones found under root, that could contain
+// a match according to root's index. Only plain literal patterns can be
+// looked up; anything else, and the flags that select files without a
+// match, keep every file.
+pub fn narrow(root: &Path, config: &Config, files: Vec<PathBuf>) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let index_path = root.join(FILE_NAME);
+    let index = Index::load(&index_path).map_err(|err| {
+        format!("can't read index {}: {} (run 'minigrep index build' first)", index_path.display(), err)
+    })?;
+
+    // Never worth searching itself, even with --hidden
+    let files = files.into_iter().filter(|path| relative_name(root, path).as_deref() != Some(FILE_NAME));
+    let Some(queries) = queries(config) else { return Ok(files.collect()) };
+
+    Ok(files
+        .filter(|path| {
+            let entry = relative_name(root, path).and_then(|name| index.files.get(&name));
+            match (entry, fs::metadata(path)) {
+                (Some(entry), Ok(metadata)) if entry.is_current(&metadata) => {
+                    queries.iter().any(|query| query.iter().all(|t| entry.trigrams.binary_search(t).is_ok()))
+                }
+                // New, changed or unreadable: let the search decide
+                _ => true,
+            }
+        })
+        .collect())
+}
+
+// The trigrams each pattern needs, or None when the index can't tell which
+// files match. A file is a candidate when it has all the trigrams of any
+// one pattern.
+fn queries(config: &Config) -> Option<Vec<Vec<u32>>> {
+    // -i folds case the Unicode way, so "strasse" matches "Straße" and
+    // "kelvin" the Kelvin sign: not the same trigrams, even lowercased
+    let literal = config.regex.is_none() && config.fuzzy.is_none() && !config.ignore_case;
+    // The index was built from the text as searched without -z or
+    // --encoding. -v and -L want the other files, and -c prints a count
+    // for every file, the ones without matches included.
+    let plain = !config.search_zip && config.encoding.is_none();
+    let wants_all = config.invert_match || config.files_without_match || config.count;
+    if !literal || !plain || wants_all || config.patterns.is_empty() {
+        return None;
+    }
+
+    config
+        .patterns
+        .iter()
+        .map(|pattern| {
+            let query = trigrams(pattern.as_bytes());
+            // Too short to rule anything out
+            if query.is_empty() { None } else { Some(query) }
+        })
+        .collect()
+}
+
+// The distinct trigrams of text, sorted, with ASCII letters lowercased
+fn trigrams(text: &[u8]) -> Vec<u32> {
+    let mut trigrams: Vec<u32> = text
+        .windows(3)
+        .map(|w| {
+            let [a, b, c] = [w[0], w[1], w[2]].map(|byte| byte.to_ascii_lowercase() as u32);
+            (a << 16) | (b << 8) | c
+        })
+        .collect();
+    trigrams.sort_unstable();
+    trigrams.dedup();
+    trigrams
+}
+
+fn read_entry(path: &Path, metadata: &Metadata) -> io::Result<Entry> {
+    // The same text a search without -z or --encoding would see
+    let text = Input::open(path, false, None, MmapChoice::Never)?.into_text()?;
+    Ok(Entry { modified: modified(metadata)?, size: metadata.len(), trigrams: trigrams(text.as_bytes()) })
+}
+
+fn modified(metadata: &Metadata) -> io::Result<(u64, u32)> {
+    let since = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+    Ok((since.as_secs(), since.subsec_nanos()))
+}
+
+// path relative to root, which is how the index names it. Paths that
+// aren't UTF-8 aren't indexed, so they are always searched.
+fn relative_name(root: &Path, path: &Path) -> Option<String> {
+    path.strip_prefix(root).ok()?.to_str().map(String::from)
+}
+
+impl Entry {
+    fn is_current(&self, metadata: &Metadata) -> bool {
+        modified(metadata).is_ok_and(|m| m == self.modified) && metadata.len() == self.size
+    }
+}
+
+impl Index {
+    fn load(path: &Path) -> io::Result<Index> {
+        let bytes = fs::read(path)?;
+        let mut rest = bytes.strip_prefix(MAGIC).ok_or_else(|| invalid("not a minigrep index"))?;
+
+        let mut files = HashMap::new();
+        while !rest.is_empty() {
+            let len = read_varint(&mut rest)? as usize;
+            if len > rest.len() {
+                return Err(invalid("truncated path"));
+            }
+            let (name, after) = rest.split_at(len);
+            let name = String::from_utf8(name.to_vec()).map_err(|_| invalid("path isn't UTF-8"))?;
+            rest = after;
+
+            let modified = (read_varint(&mut rest)?, read_varint(&mut rest)? as u32);
+            let size = read_varint(&mut rest)?;
+            let count = read_varint(&mut rest)? as usize;
+            let mut trigrams = Vec::with_capacity(count.min(rest.len()));
+            let mut trigram = 0;
+            for _ in 0..count {
+                trigram += read_varint(&mut rest)? as u32;
+                trigrams.push(trigram);
+            }
+            files.insert(name, Entry { modified, size, trigrams });
+        }
+        Ok(Index { files })
+    }
+
+    // Writes the index to a temporary file next to path first, so a search
+    // never sees a half-written index
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let mut bytes = MAGIC.to_vec();
+        let mut names: Vec<_> = self.files.keys().collect();
+        names.sort();
+        for name in names {
+            let entry = &self.files[name];
+            write_varint(&mut bytes, name.len() as u64);
+            bytes.extend_from_slice(name.as_bytes());
+            write_varint(&mut bytes, entry.modified.0);
+            write_varint(&mut bytes, entry.modified.1 as u64);
+            write_varint(&mut bytes, entry.size);
+            write_varint(&mut bytes, entry.trigrams.len() as u64);
+            let mut previous = 0;
+            for &trigram in &entry.trigrams {
+                write_varint(&mut bytes, (trigram - previous) as u64);
+                previous = trigram;
+            }
+        }
+
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let mut temp = NamedTempFile::new_in(dir)?;
+        temp.write_all(&bytes)?;
+        temp.as_file().sync_all()?;
+        temp.persist(path).map_err(|err| err.error)?;
+        Ok(())
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn read_varint(bytes: &mut &[u8]) -> io::Result<u64> {
+    let mut n = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first().ok_or_else(|| invalid("truncated number"))?;
+        *bytes = rest;
+        n |= ((byte & 0x7f) as u64) << shift;
+        if byte < 0x80 {
+            return Ok(n);
+        }
+    }
+    Err(invalid("number too long"))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_args;
+
+    fn config(flags: &[&str], root: &Path) -> Config {
+        let root = root.display().to_string();
+        parse_args(&[flags, &[root.as_str()]].concat()).unwrap()
+    }
+
+    fn names(root: &Path, files: Vec<PathBuf>) -> Vec<String> {
+        files.iter().filter_map(|path| relative_name(root, path)).collect()
+    }
+
+    #[test]
+    fn narrows_to_files_with_every_trigram() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("a.txt"), "Rust:\nsafe, fast, productive.\n").unwrap();
+        fs::write(root.join("b.txt"), "Pick three.\n").unwrap();
+        fs::write(root.join("c.txt"), "Trust me, rust is fast.\n").unwrap();
+
+        let stats = build(root, &config(&["index", "build"], root)).unwrap();
+        assert_eq!(BuildStats { files: 3, read: 3 }, stats);
+
+        let narrowed = |flags: &[&str]| {
+            let config = config(flags, root);
+            let files = walk::files(root, &config).unwrap();
+            names(root, narrow(root, &config, files).unwrap())
+        };
+        assert_eq!(vec!["a.txt", "c.txt"], narrowed(&["fast"]));
+        assert_eq!(vec!["b.txt"], narrowed(&["three"]));
+        assert_eq!(vec!["a.txt", "b.txt"], narrowed(&["-e", "safe", "-e", "Pick"]));
+        assert!(narrowed(&["missing"]).is_empty());
+
+        // The index can't help with these, so every file is a candidate
+        let cannot_narrow = [
+            &["-E", "fa.t"][..],
+            &["fa"],
+            &["-v", "fast"],
+            &["-c", "fast"],
+            &["-i", "RUST"],
+            &["--fuzzy", "1", "fast"],
+        ];
+        for flags in cannot_narrow {
+            assert_eq!(vec!["a.txt", "b.txt", "c.txt"], narrowed(flags), "{:?}", flags);
+        }
+    }
+
+    #[test]
+    fn ignoring_case_finds_what_a_plain_search_does() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("a.txt"), "Grüße aus der Straße\n").unwrap();
+        fs::write(root.join("b.txt"), "nothing here\n").unwrap();
+        build(root, &config(&["index", "build"], root)).unwrap();
+
+        let config = config(&["-i", "strasse"], root);
+        let files = walk::files(root, &config).unwrap();
+        assert_eq!(vec!["a.txt", "b.txt"], names(root, narrow(root, &config, files).unwrap()));
+    }
+
+    #[test]
+    fn refreshes_changed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("a.txt"), "safe, fast, productive.\n").unwrap();
+        fs::write(root.join("b.txt"), "Pick three.\n").unwrap();
+        build(root, &config(&["index", "build"], root)).unwrap();
+
+        fs::write(root.join("b.txt"), "Pick three, or four.\n").unwrap();
+        fs::write(root.join("new.txt"), "four\n").unwrap();
+
+        // Changed and new files are searched before the index catches up
+        let config = config(&["four"], root);
+        let files = walk::files(root, &config).unwrap();
+        assert_eq!(vec!["b.txt", "new.txt"], names(root, narrow(root, &config, files).unwrap()));
+
+        let stats = build(root, &config).unwrap();
+        assert_eq!(BuildStats { files: 3, read: 2 }, stats);
+    }
+
+    #[test]
+    fn round_trips_through_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(FILE_NAME);
+        let mut index = Index::default();
+        let entry = Entry { modified: (1_700_000_000, 999_999_999), size: 1 << 40, trigrams: trigrams(b"a\xffz Rust") };
+        index.files.insert("dir/ü.txt".to_string(), entry.clone());
+        index.save(&path).unwrap();
+
+        assert_eq!(Some(&entry), Index::load(&path).unwrap().files.get("dir/ü.txt"));
+
+        fs::write(&path, b"something else").unwrap();
+        assert!(Index::load(&path).is_err());
+        let mut truncated = MAGIC.to_vec();
+        truncated.push(200);
+        fs::write(&path, truncated).unwrap();
+        assert!(Index::load(&path).is_err());
+    }
+}
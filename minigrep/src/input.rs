@@ -0,0 +1,508 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+
+use bzip2::bufread::MultiBzDecoder;
+use encoding_rs::Encoding;
+use encoding_rs_io::DecodeReaderBytesBuilder;
+use flate2::bufread::MultiGzDecoder;
+use memchr::memchr;
+use memchr::memmem::Finder;
+use xz2::bufread::XzDecoder;
+
+use crate::mmap::Mapped;
+use crate::scan::{lines_containing, spans_containing};
+use crate::{lines, Line};
+
+// Files at least this big are searched a line at a time instead of being
+// read into memory first
+pub const STREAM_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+// Files at least this big are memory mapped rather than read, unless
+// --no-mmap says otherwise. Smaller ones are quicker to read than to map.
+pub const MMAP_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+// How much of a stream or a mapped file is checked for NUL bytes
+const BINARY_CHECK_LEN: usize = 64 * 1024;
+
+// Whether to memory map the files searched (--mmap, --no-mmap)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MmapChoice {
+    Never,
+    // Map files of MMAP_THRESHOLD bytes or more
+    #[default]
+    Auto,
+    Always,
+}
+
+// Where the lines of one search come from. Either way the text has been
+// converted to UTF-8 already: from UTF-16 if it starts with a UTF-16 byte
+// order mark, or from the --encoding given. Anything that still isn't valid
+// UTF-8 is decoded lossily rather than failing the whole search.
+pub enum Input<'a> {
+    // The whole file, read up front
+    Text(String),
+    // A reader that is consumed one line at a time, so memory use doesn't
+    // grow with its size. Files are read with their own readers, but a
+    // Searcher may be handed one that borrows.
+    Stream(Box<dyn BufRead + 'a>),
+    // A file mapped into memory, searched in place; start is past its
+    // UTF-8 byte order mark, if it has one. Only the lines that are looked
+    // at get copied out, and converted to UTF-8 lossily.
+    Mapped { map: Mapped, start: usize },
+}
+
+// The compressed formats logs tend to be rotated into, which are searched
+// by decompressing them on the fly. The format is told by the magic bytes
+// at the start of the data rather than trusted from the file name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    const EXTENSIONS: [&'static str; 4] = ["gz", "bz2", "xz", "zst"];
+
+    fn from_magic(start: &[u8]) -> Option<Compression> {
+        if start.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if start.starts_with(b"BZh") {
+            Some(Compression::Bzip2)
+        } else if start.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Compression::Xz)
+        } else if start.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    // The decompressed contents of reader. Concatenated streams (as left
+    // by cat a.gz b.gz) are all decompressed, like gzip -d does.
+    fn decoder(self, reader: impl BufRead + 'static) -> io::Result<Box<dyn BufRead>> {
+        Ok(match self {
+            Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+            Compression::Bzip2 => Box::new(BufReader::new(MultiBzDecoder::new(reader))),
+            Compression::Xz => Box::new(BufReader::new(XzDecoder::new_multi_decoder(reader))),
+            Compression::Zstd => Box::new(BufReader::new(zstd::Decoder::with_buffer(reader)?)),
+        })
+    }
+}
+
+// Converts reader to UTF-8 from encoding, or from whatever its byte order
+// mark says. Without either, the bytes are passed through as they are.
+fn decoded(reader: impl Read + 'static, encoding: Option<&'static Encoding>) -> Box<dyn BufRead> {
+    let decoder = DecodeReaderBytesBuilder::new()
+        .encoding(encoding)
+        .strip_bom(true)
+        .build(reader);
+    Box::new(BufReader::new(decoder))
+}
+
+impl Input<'_> {
+    // Opens path for searching; "-" means standard input. Files named
+    // like compressed files (.gz, .bz2, .xz, .zst) are decompressed if
+    // their contents agree; with search_zip (-z) any input that starts
+    // like compressed data is, standard input included. encoding (from
+    // --encoding) overrides the detection of UTF-16 by byte order mark.
+    // mmap says which files to map instead of reading them.
+    pub fn open(
+        path: &Path,
+        search_zip: bool,
+        encoding: Option<&'static Encoding>,
+        mmap: MmapChoice,
+    ) -> io::Result<Input<'static>> {
+        let (mut reader, len): (Box<dyn BufRead>, u64) = if path == Path::new("-") {
+            (Box::new(io::stdin().lock()), 0)
+        } else {
+            let file = File::open(path)?;
+            let metadata = file.metadata()?;
+            let wanted = match mmap {
+                MmapChoice::Never => false,
+                MmapChoice::Auto => metadata.len() >= MMAP_THRESHOLD,
+                MmapChoice::Always => true,
+            };
+            // Mapping only pays off when the bytes can be searched as they
+            // are, and only regular files can be mapped
+            let as_is = !search_zip && !named_compressed(path) && encoding.is_none();
+            if wanted && as_is && metadata.is_file() && metadata.len() > 0 {
+                if let Some(input) = Mapped::new(&file).and_then(Input::mapped) {
+                    return Ok(input);
+                }
+            }
+            (Box::new(BufReader::new(file)), metadata.len())
+        };
+
+        let mut compressed = false;
+        if search_zip || named_compressed(path) {
+            // Peeking with fill_buf leaves the magic bytes in the reader
+            if let Some(compression) = Compression::from_magic(reader.fill_buf()?) {
+                reader = compression.decoder(reader)?;
+                compressed = true;
+            }
+        }
+
+        let mut reader = decoded(reader, encoding);
+        if path == Path::new("-") || compressed || len >= STREAM_THRESHOLD {
+            return Ok(Input::Stream(reader));
+        }
+
+        let mut bytes = Vec::with_capacity(len as usize);
+        reader.read_to_end(&mut bytes)?;
+        let contents = match String::from_utf8(bytes) {
+            Ok(contents) => contents,
+            Err(err) => String::from_utf8_lossy(err.as_bytes()).into_owned(),
+        };
+        Ok(Input::Text(contents))
+    }
+
+    // map as an Input, unless it starts with a UTF-16 byte order mark and
+    // so has to be decoded after all
+    fn mapped(map: Mapped) -> Option<Input<'static>> {
+        let bytes = map.bytes();
+        if bytes.starts_with(&[0xff, 0xfe]) || bytes.starts_with(&[0xfe, 0xff]) {
+            return None;
+        }
+        let start = if bytes.starts_with(&[0xef, 0xbb, 0xbf]) { 3 } else { 0 };
+        Some(Input::Mapped { map, start })
+    }
+
+    // Whether this looks like a binary file rather than text: text never
+    // has NUL bytes in it, binary files nearly always do. Text in memory is
+    // checked all the way through, a stream or a mapped file only as far as
+    // its first block.
+    pub fn is_binary(&mut self) -> io::Result<bool> {
+        Ok(match self {
+            Input::Text(contents) => memchr(0, contents.as_bytes()).is_some(),
+            Input::Stream(reader) => memchr(0, reader.fill_buf()?).is_some(),
+            Input::Mapped { map, start } => {
+                let bytes = &map.bytes()[*start..];
+                memchr(0, &bytes[..bytes.len().min(BINARY_CHECK_LEN)]).is_some()
+            }
+        })
+    }
+
+    // Everything left to read, as one string (-U)
+    pub fn into_text(self) -> io::Result<String> {
+        match self {
+            Input::Text(contents) => Ok(contents),
+            Input::Stream(mut reader) => {
+                let mut bytes = Vec::new();
+                reader.read_to_end(&mut bytes)?;
+                Ok(match String::from_utf8(bytes) {
+                    Ok(contents) => contents,
+                    Err(err) => String::from_utf8_lossy(err.as_bytes()).into_owned(),
+                })
+            }
+            Input::Mapped { map, start } => {
+                let contents = String::from_utf8_lossy(&map.bytes()[start..]).into_owned();
+                check_truncated(&map)?;
+                Ok(contents)
+            }
+        }
+    }
+
+    // Like for_each_line, but f only needs to see the lines that contain
+    // finder's needle. Text that is already in memory or mapped is searched
+    // as a whole for it, skipping the other lines; a stream still goes
+    // through every line.
+    pub fn for_each_line_containing(
+        self,
+        finder: &Finder,
+        mut f: impl FnMut(&Line) -> io::Result<bool>,
+    ) -> io::Result<()> {
+        match self {
+            Input::Text(contents) => {
+                for m in lines_containing(finder, &contents) {
+                    if !f(&m)? {
+                        break;
+                    }
+                }
+                Ok(())
+            }
+            Input::Mapped { map, start } => {
+                let bytes = &map.bytes()[start..];
+                let mut buf = Vec::new();
+                for (line_number, span) in spans_containing(finder, bytes) {
+                    buf.clear();
+                    buf.extend_from_slice(&bytes[span.clone()]);
+                    if !emit(&mut f, line_number, span.start, &buf)? {
+                        break;
+                    }
+                }
+                check_truncated(&map)
+            }
+            stream => stream.for_each_line(f),
+        }
+    }
+
+    // Calls f with every line in turn, stopping early once f returns false
+    pub fn for_each_line(self, mut f: impl FnMut(&Line) -> io::Result<bool>) -> io::Result<()> {
+        match self {
+            Input::Text(contents) => {
+                for m in lines(&contents) {
+                    if !f(&m)? {
+                        break;
+                    }
+                }
+            }
+            Input::Stream(mut reader) => {
+                // One buffer is reused for every line, so only the longest
+                // line has to fit in memory
+                let mut buf = Vec::new();
+                let mut line_number = 0;
+                let mut byte_offset = 0;
+                loop {
+                    buf.clear();
+                    let read = reader.read_until(b'\n', &mut buf)?;
+                    if read == 0 {
+                        break;
+                    }
+                    line_number += 1;
+
+                    if !emit(&mut f, line_number, byte_offset, &buf)? {
+                        break;
+                    }
+
+                    byte_offset += read;
+                }
+            }
+            Input::Mapped { map, start } => {
+                // Each line is copied out of the map before it's used, so
+                // the file changing underneath can't change a line that is
+                // being looked at
+                let bytes = &map.bytes()[start..];
+                let mut buf = Vec::new();
+                let mut line_number = 0;
+                let mut byte_offset = 0;
+                while byte_offset < bytes.len() {
+                    let end = memchr(b'\n', &bytes[byte_offset..]).map_or(bytes.len(), |i| byte_offset + i + 1);
+                    line_number += 1;
+
+                    buf.clear();
+                    buf.extend_from_slice(&bytes[byte_offset..end]);
+                    if !emit(&mut f, line_number, byte_offset, &buf)? {
+                        break;
+                    }
+
+                    byte_offset = end;
+                }
+                check_truncated(&map)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Passes one line that was read as bytes to f, without its terminator and
+// converted to UTF-8 lossily
+pub fn emit(
+    f: &mut impl FnMut(&Line) -> io::Result<bool>,
+    line_number: usize,
+    byte_offset: usize,
+    raw: &[u8],
+) -> io::Result<bool> {
+    let mut end = raw.len();
+    if raw[..end].ends_with(b"\n") {
+        end -= 1;
+        if raw[..end].ends_with(b"\r") {
+            end -= 1;
+        }
+    }
+    let text = String::from_utf8_lossy(&raw[..end]);
+    f(&Line { line_number, byte_offset, line: &text })
+}
+
+// Whether path is named like one of the compressed formats
+pub fn named_compressed(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| Compression::EXTENSIONS.iter().any(|e| ext == *e))
+}
+
+// A mapped file that shrank while it was searched has had zeros searched
+// in place of its end, which is worth an error rather than a crash
+fn check_truncated(map: &Mapped) -> io::Result<()> {
+    if map.truncated() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "file was truncated while it was being searched",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect(input: Input) -> Vec<(usize, usize, String)> {
+        let mut seen = Vec::new();
+        input
+            .for_each_line(|m| {
+                seen.push((m.line_number, m.byte_offset, m.line.to_string()));
+                Ok(true)
+            })
+            .unwrap();
+        seen
+    }
+
+    #[test]
+    fn stream_matches_in_memory_lines() {
+        let contents = "Rust:\r\nsafe, fast, productive.\nPick three.";
+
+        assert_eq!(
+            collect(Input::Text(contents.to_string())),
+            collect(Input::Stream(Box::new(contents.as_bytes())))
+        );
+    }
+
+    #[test]
+    fn stream_tolerates_invalid_utf8() {
+        let bytes: &'static [u8] = b"caf\xe9\nok\n";
+
+        assert_eq!(
+            vec![(1, 0, "caf\u{fffd}".to_string()), (2, 5, "ok".to_string())],
+            collect(Input::Stream(Box::new(bytes)))
+        );
+    }
+
+    #[test]
+    fn decompresses_by_magic_bytes() {
+        use std::io::Write;
+
+        let contents = "Rust:\nsafe, fast, productive.\n";
+        let dir = tempfile::tempdir().unwrap();
+        let plain = collect(Input::Text(contents.to_string()));
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(contents.as_bytes()).unwrap();
+        let mut bz = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        bz.write_all(contents.as_bytes()).unwrap();
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+        xz.write_all(contents.as_bytes()).unwrap();
+        let compressed = [
+            ("log.gz", gz.finish().unwrap()),
+            ("log.bz2", bz.finish().unwrap()),
+            ("log.xz", xz.finish().unwrap()),
+            ("log.zst", zstd::encode_all(contents.as_bytes(), 0).unwrap()),
+        ];
+
+        for (name, bytes) in compressed {
+            let path = dir.path().join(name);
+            std::fs::write(&path, &bytes).unwrap();
+            assert_eq!(plain, collect(Input::open(&path, false, None, MmapChoice::Never).unwrap()), "{}", name);
+
+            // Without the extension it takes -z to look inside
+            let renamed = dir.path().join(name.replace('.', "-"));
+            std::fs::rename(&path, &renamed).unwrap();
+            assert_eq!(plain, collect(Input::open(&renamed, true, None, MmapChoice::Never).unwrap()), "{}", name);
+        }
+
+        // A file that only looks compressed by name is searched as it is
+        let fake = dir.path().join("fake.gz");
+        std::fs::write(&fake, contents).unwrap();
+        assert_eq!(plain, collect(Input::open(&fake, false, None, MmapChoice::Never).unwrap()));
+    }
+
+    #[test]
+    fn decodes_utf16_and_latin1() {
+        let dir = tempfile::tempdir().unwrap();
+        let expected = vec![(1, 0, "café".to_string()), (2, 6, "naïve".to_string())];
+
+        let mut utf16 = vec![0xff, 0xfe];
+        utf16.extend("café\nnaïve\n".encode_utf16().flat_map(|unit| unit.to_le_bytes()));
+        let path = dir.path().join("utf16.txt");
+        std::fs::write(&path, utf16).unwrap();
+        let mut input = Input::open(&path, false, None, MmapChoice::Never).unwrap();
+        assert!(!input.is_binary().unwrap());
+        assert_eq!(expected, collect(input));
+
+        let path = dir.path().join("latin1.txt");
+        std::fs::write(&path, b"caf\xe9\nna\xefve\n").unwrap();
+        let latin1 = Encoding::for_label(b"latin1");
+        assert_eq!(expected, collect(Input::open(&path, false, latin1, MmapChoice::Never).unwrap()));
+
+        // Without --encoding the same bytes aren't valid UTF-8, which is
+        // searched lossily instead of being an error
+        let lossy = collect(Input::open(&path, false, None, MmapChoice::Never).unwrap());
+        assert_eq!("caf\u{fffd}", lossy[0].2);
+    }
+
+    #[test]
+    fn nul_bytes_mean_binary() {
+        assert!(Input::Text("ELF\0\0\x01".to_string()).is_binary().unwrap());
+        assert!(Input::Stream(Box::new(&b"\x7fELF\x00\x01"[..])).is_binary().unwrap());
+        assert!(!Input::Text("plain text".to_string()).is_binary().unwrap());
+    }
+
+    #[test]
+    fn stops_when_asked() {
+        let mut calls = 0;
+        Input::Stream(Box::new(&b"a\nb\nc\n"[..]))
+            .for_each_line(|_| {
+                calls += 1;
+                Ok(false)
+            })
+            .unwrap();
+
+        assert_eq!(1, calls);
+    }
+
+    #[test]
+    fn mapped_files_read_like_text() {
+        let contents = "\u{feff}Rust:\r\nsafe, fast, productive.\nPick three.";
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("poem.txt");
+        std::fs::write(&path, contents).unwrap();
+
+        let mut input = Input::open(&path, false, None, MmapChoice::Always).unwrap();
+        if !matches!(input, Input::Mapped { .. }) {
+            // Nothing to compare on platforms that don't map files
+            return;
+        }
+        assert!(!input.is_binary().unwrap());
+        let text = contents.trim_start_matches('\u{feff}').to_string();
+        assert_eq!(collect(Input::Text(text.clone())), collect(input));
+
+        let finder = Finder::new("fast");
+        let mut found = Vec::new();
+        Input::open(&path, false, None, MmapChoice::Always)
+            .unwrap()
+            .for_each_line_containing(&finder, |m| {
+                found.push((m.line_number, m.byte_offset, m.line.to_string()));
+                Ok(true)
+            })
+            .unwrap();
+        assert_eq!(vec![(2, 7, "safe, fast, productive.".to_string())], found);
+
+        // Small files are only mapped when asked to be
+        assert!(matches!(Input::open(&path, false, None, MmapChoice::Auto).unwrap(), Input::Text(_)));
+        assert!(matches!(Input::open(&path, false, None, MmapChoice::Never).unwrap(), Input::Text(_)));
+    }
+
+    #[test]
+    fn truncation_while_mapped_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("big.log");
+        std::fs::write(&path, "line of text\n".repeat(100_000)).unwrap();
+
+        let input = Input::open(&path, false, None, MmapChoice::Always).unwrap();
+        if !matches!(input, Input::Mapped { .. }) {
+            return;
+        }
+        std::fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(10).unwrap();
+
+        let mut lines = 0;
+        let err = input
+            .for_each_line(|_| {
+                lines += 1;
+                Ok(true)
+            })
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+        assert!(lines >= 1);
+    }
+}
@@ -1,46 +1,79 @@
+use std::collections::BTreeMap;
 use std::error::Error;
-use std::fs;
 use std::env;
+use std::io::{self, BufWriter, IsTerminal, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Instant;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+use encoding_rs::Encoding;
+use memchr::memmem::Finder;
+use regex::{Regex, RegexSet};
 
-    #[test]
-    fn case_sensitive() {
-        let query = "duct";
-        // Note that the backslash after the opening double quote tells 
-        // Rust not to put a newline character at the beginning of the 
-        // contents of this string literal)
-        let contents = "\
-Rust:
-safe, fast, productive.
-Pick three.
-Duct tape.";
+mod cli;
+mod config_file;
+mod fields;
+mod fold;
+mod follow;
+mod fuzzy;
+mod index;
+mod input;
+mod matcher;
+mod mmap;
+mod pool;
+mod printer;
+mod rewrite;
+mod scan;
+pub mod searcher;
+mod sink;
+mod walk;
 
-        assert_eq!(vec!["safe, fast, productive."], search(query, contents));
-    }
+pub use cli::{usage, ArgsError, ColorChoice};
+use fold::FoldedLiteral;
+use input::Input;
+pub use input::MmapChoice;
+pub use matcher::Matcher;
+use pool::ThreadPool;
+use printer::Printer;
+pub use searcher::{Match, Searcher};
+pub use sink::Sink;
 
-    #[test]
-    fn case_insensitive() {
-        let query = "rUsT";
-        let contents = "\
-Rust:
-safe, fast, productive.
-Pick three.
-Trust me.";
+// One line of the searched contents, together with where it was found.
+// line_number is 1-based like grep -n; byte_offset is the offset of the
+// first byte of the line from the start of the contents, like grep -b.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Line<'a> {
+    pub line_number: usize,
+    pub byte_offset: usize,
+    pub line: &'a str,
+}
 
-        assert_eq!(
-            vec!["Rust:", "Trust me."],
-            search_case_insensitive(query, contents)
-        );
-    }
+// Like contents.lines(), but keeps track of each line's number and byte
+// offset. Line terminators (\n or \r\n) are not part of the yielded line.
+pub fn lines(contents: &str) -> impl Iterator<Item = Line<'_>> {
+    let mut byte_offset = 0;
+    contents
+        .split_inclusive('\n')
+        .enumerate()
+        .map(move |(i, raw)| {
+            let start = byte_offset;
+            byte_offset += raw.len();
+
+            let line = match raw.strip_suffix('\n') {
+                Some(line) => line.strip_suffix('\r').unwrap_or(line),
+                None => raw,
+            };
+            Line { line_number: i + 1, byte_offset: start, line }
+        })
 }
 
 // we tell Rust that the data returned by the search function will 
 // live as long as the data passed into the search function in the contents argument
 //  by defining an explicit lifetime 'a in the signature of search
-pub fn search<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
+pub fn search<'a>(query: &str, contents: &'a str) -> Vec<Line<'a>> {
     //let mut results = Vec::new();
 
     //  helpful method to handle line-by-line iteration of strings, conveniently named lines
@@ -60,42 +93,144 @@ pub fn search<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
     // the matching lines into another vector with collect. Much simpler! Feel free to make 
     // the same change to use iterator methods in the search_case_insensitive function as 
     // well
-    contents
-        .lines()
-            .filter(|line| line.contains(query))
-                .collect()
+    // Filtering still splits the whole file into lines and tests each one.
+    // lines_containing searches the whole buffer for the query instead and
+    // only works out the lines around each hit; benches/search.rs compares
+    // the two. A query with a line break in it can't be found that way.
+    if query.contains(['\n', '\r']) {
+        return lines(contents).filter(|m| m.line.contains(query)).collect();
+    }
+    let finder = Finder::new(query);
+    scan::lines_containing(&finder, contents).collect()
 }
 
 pub fn search_case_insensitive<'a>(
     query: &str,
     contents: &'a str,
-) -> Vec<&'a str> {
-    // Note that query is now a String rather than a string slice, 
-    // because calling to_lowercase creates new data rather than 
-    // referencing existing data
-    let query = query.to_lowercase();
+) -> Vec<Line<'a>> {
+    // Lowercasing both sides (query.to_lowercase() and line.to_lowercase())
+    // allocates a new String for every line and still misses matches like
+    // "ß" and "SS", which lowercase differently but are the same word.
+    // FoldedLiteral compares Unicode case folds instead, folding each line
+    // as it goes without copying it.
+    let query = FoldedLiteral::new(query);
     let mut results = Vec::new();
 
-    for line in contents.lines() {
-        //  When we pass query as an argument to the contains 
-        // method now, we need to add an ampersand because the 
-        // signature of contains is defined to take a string slice.
-        if line.to_lowercase().contains(&query) {
-            results.push(line);
+    for m in lines(contents) {
+        if query.is_match(m.line) {
+            results.push(m);
         }
     }
 
     results
 }
 
+// The query has already been compiled into a Regex by Config::build, so
+// every line is tested against the same automaton instead of re-parsing
+// the pattern per line. Anchors like ^ and $ apply to each line because
+// lines() has already stripped the line terminator.
+pub fn search_regex<'a>(re: &Regex, contents: &'a str) -> Vec<Line<'a>> {
+    lines(contents)
+            .filter(|m| re.is_match(m.line))
+                .collect()
+}
+
+#[derive(Default)]
 pub struct Config {
-    pub query: String,
+    // Every pattern to look for: the positional PATTERN, or each -e and
+    // each line of each -f file, in the order given
+    pub patterns: Vec<String>,
+    // The file or directory to search; "-" (the default) is standard input
     pub file_path: String,
     pub ignore_case: bool,
+    // -S: work out ignore_case from the patterns, ignoring case unless
+    // one of them has an uppercase letter
+    pub smart_case: bool,
+    // The compiled form of patterns whenever the literal searches can't
+    // handle them: with -E / --regex, or with several patterns and -i, -w
+    // or -x. None keeps the literal searches.
+    pub regex: Option<Regex>,
+    // The same patterns one by one, when several of them are in regex and
+    // --show-pattern needs to know which ones matched
+    pub regex_set: Option<RegexSet>,
+    // -w: the match must be a whole word
+    pub word_regexp: bool,
+    // -x: the match must be the whole line
+    pub line_regexp: bool,
+    // --fuzzy K: the pattern also matches text up to K edits away from it
+    pub fuzzy: Option<usize>,
+    // minigrep index build: write file_path's trigram index instead of
+    // searching it
+    pub build_index: bool,
+    // --index: narrow a directory search down with its trigram index
+    pub index: bool,
+    // -U: patterns are matched against the whole input rather than line
+    // by line, so a match may span several lines
+    pub multiline: bool,
+    // -v: select the lines that don't match
+    pub invert_match: bool,
+    // -c / -l / -L: print a count, or just the file name, instead of the lines
+    pub count: bool,
+    pub files_with_matches: bool,
+    pub files_without_match: bool,
+    // -o: print only the matched parts of the lines
+    pub only_matching: bool,
+    // --show-pattern: prefix selected lines with the patterns they matched
+    pub show_pattern: bool,
+    // -m: stop reading a file after this many selected lines
+    pub max_count: Option<usize>,
+    // --json: print JSON Lines events instead of text
+    pub json: bool,
+    // -r / --replace: print selected lines with each match replaced by
+    // this. $1 and ${name} refer to capture groups when the patterns are
+    // regexes (-E); otherwise any $ has already been escaped as $$.
+    pub replace: Option<String>,
+    // --in-place: write the replaced lines back to the files, or with
+    // --dry-run only show the changes as a diff
+    pub in_place: bool,
+    pub dry_run: bool,
+    pub color: ColorChoice,
+    // When file_path is a directory: also search hidden files (--hidden)
+    // and/or disregard .gitignore/.ignore rules (--no-ignore)
+    pub hidden: bool,
+    pub no_ignore: bool,
+    // --glob: include (or with a leading !, exclude) matching paths
+    pub globs: Vec<String>,
+    // -t / -T: file types to search, and to skip; --type-add NAME:GLOB
+    // defines a type, or adds a glob to an existing one
+    pub types: Vec<String>,
+    pub types_not: Vec<String>,
+    pub type_defs: Vec<String>,
+    // -z: decompress any input that starts like compressed data, not just
+    // files named .gz, .bz2, .xz or .zst
+    pub search_zip: bool,
+    // -a / --text: print matching lines of binary files (ones with NUL
+    // bytes) too, instead of just saying that the file matches
+    pub text: bool,
+    // --encoding: what to decode input from when it isn't UTF-8. None
+    // still decodes UTF-16 files that start with a byte order mark.
+    pub encoding: Option<&'static Encoding>,
+    // --mmap / --no-mmap: whether files are memory mapped rather than
+    // read; by default only big ones are
+    pub mmap: MmapChoice,
+    // --follow: keep searching the file as it grows, like tail -f | grep
+    pub follow: bool,
+    // --field: search each line as a CSV or JSON Lines record, only in
+    // the fields named; NAME=VALUE also requires NAME to be VALUE
+    pub fields: Vec<String>,
+    // How many files of a directory to search at once (-j / --threads);
+    // 0 means one per available CPU
+    pub threads: usize,
+    // Output decorations: -n line numbers, -b byte offsets, and the
+    // number of context lines to print after (-A) and before (-B) a match
+    pub line_number: bool,
+    pub byte_offset: bool,
+    pub after_context: usize,
+    pub before_context: usize,
 }
 
 impl Config {
-    /**
+    /*
      *  We can create instances of types in the standard library, 
      *  such as String, by calling String::new. Similarly, by changing 
      *  parse_config into a new function associated with Config, we’ll be 
@@ -112,23 +247,11 @@ impl Config {
      //  Trait syntax we discussed in the “Traits as Parameters” section of
      // Chapter 10 means that args can be any type that implements the Iterator
      //  type and returns String items
-    pub fn build(mut args: impl Iterator<Item = String>) -> Result<Config, &'static str> {
+    pub fn build(mut args: impl Iterator<Item = String>) -> Result<Config, ArgsError> {
 
         args.next(); //  ignore the first value in the return value of env::args
                      // which is the name of the program
 
-        let query = match args.next() {
-            Some(arg) => arg,
-            None => return Err("Didn't get a query string"),
-        };
-            
-        let file_path = match args.next() {
-            Some(arg) => arg,
-            None => return Err("Didn't get a file path"),
-        };
-            
-
-        
         // Here, we create a new variable ignore_case. To set its value, 
         // we call the env::var function and pass it the name of the IGNORE_CASE 
         // environment variable. The env::var function returns a Result that will 
@@ -144,8 +267,25 @@ impl Config {
         // set or unset, so we’re checking is_ok rather than using unwrap, expect, 
         // or any of the other methods we’ve seen on Result.
         let ignore_case = env::var("IGNORE_CASE").is_ok();
+        // NO_COLOR (https://no-color.org) counts when it's set to anything
+        // but the empty string
+        let no_color = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+
+        // The config file provides defaults, IGNORE_CASE and NO_COLOR
+        // override those, and flags on the command line override both
+        let file_args = match env::var_os("MINIGREP_CONFIG") {
+            Some(path) if !path.is_empty() => config_file::args(Path::new(&path))?,
+            _ => Vec::new(),
+        };
+        cli::parse(file_args, cli::Env { ignore_case, no_color }, args)
+    }
 
-        Ok(Config { query, file_path, ignore_case})
+    // The same flags as build takes, minus the program name in front, and
+    // nothing else: the environment and MINIGREP_CONFIG are left out, so a
+    // program that searches with minigrep gets the same Config wherever it
+    // runs
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Config, ArgsError> {
+        cli::parse(Vec::new(), cli::Env::default(), args)
     }
 }
 
@@ -159,7 +299,7 @@ impl Config {
 // return value will be. This gives us flexibility to return error values 
 // that may be of different types in different error cases. The dyn keyword is 
 // short for “dynamic.”
-pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
+pub fn run(mut config: Config) -> Result<(), Box<dyn Error>> {
 
     // fs::read_to_string takes the filename, opens that file, 
     // and returns a Result<String> of the file’s contents
@@ -168,16 +308,49 @@ pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
     // as we talked about in Chapter 9. Rather than panic! on an error, 
     // ? will return the error value from the current function for the 
     // caller to handle.
-    let contents = fs::read_to_string(config.file_path)?;
+    config.color = config.color.resolve(io::stdout().is_terminal());
+
+    if config.build_index {
+        let root = Path::new(&config.file_path);
+        let stats = index::build(root, &config)?;
+        println!(
+            "Indexed {} files ({} read) into {}",
+            stats.files,
+            stats.read,
+            root.join(index::FILE_NAME).display()
+        );
+        return Ok(());
+    }
 
-    let results = if config.ignore_case {
-        search_case_insensitive(&config.query, &contents)
+    // Shared with the worker threads when searching a directory
+    let config = Arc::new(config);
+    let matcher = Arc::new(Matcher::new(&config)?);
+    let stdout = io::stdout();
+
+    let result = if config.follow {
+        // Lines are printed as they turn up, so standard output's own line
+        // buffering is all there is
+        let mut printer = Printer::new(stdout.lock(), &config);
+        follow::file(&config, &matcher, &mut printer, Path::new(&config.file_path)).map_err(Into::into)
     } else {
-        search(&config.query, &contents)
+        let mut printer = Printer::new(BufWriter::new(stdout.lock()), &config);
+        let start = Instant::now();
+        search_root(&config, &matcher, &mut printer)
+            .and_then(|()| {
+                if config.json {
+                    printer.summary(start.elapsed())?;
+                }
+                Ok(printer.flush()?)
+            })
     };
 
-    for line in results {
-        println!("{}", line);
+    // When the reader of a pipe goes away (minigrep ... | head) there is
+    // nobody left to print for, which isn't an error worth reporting
+    if let Err(err) = result {
+        match err.downcast_ref::<io::Error>() {
+            Some(err) if err.kind() == io::ErrorKind::BrokenPipe => {}
+            _ => return Err(err),
+        }
     }
 
     // Third, the run function now returns an Ok value in 
@@ -189,3 +362,494 @@ pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
     // calling run for its side effects only; it doesn’t return a value we need.
     Ok(())
 }
+
+// Searches standard input, a single file, or every file under a directory
+fn search_root<W: Write>(
+    config: &Arc<Config>,
+    matcher: &Arc<Matcher>,
+    printer: &mut Printer<W>,
+) -> Result<(), Box<dyn Error>> {
+    let root = Path::new(&config.file_path);
+
+    if config.file_path == "-" {
+        let input = Input::open(root, config.search_zip, config.encoding, config.mmap)?;
+        search_input(config, matcher, printer, Path::new("(standard input)"), false, input)?;
+    } else if root.is_dir() {
+        let mut files = walk::files(root, config)?;
+        if config.index {
+            files = index::narrow(root, config, files)?;
+        }
+        search_files(config, matcher, printer, files)?;
+    } else {
+        search_path(config, matcher, printer, root, false)?;
+    }
+
+    Ok(())
+}
+
+// Searches a list of files on a pool of worker threads. Each file's output
+// is collected in memory and printed once every file before it has been
+// printed, so the result reads the same as a one-file-at-a-time search.
+// Every line is prefixed with the file it came from, and files that can't
+// be read are reported and skipped.
+fn search_files<W: Write>(
+    config: &Arc<Config>,
+    matcher: &Arc<Matcher>,
+    printer: &mut Printer<W>,
+    files: Vec<PathBuf>,
+) -> io::Result<()> {
+    let threads = match config.threads {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    let pool = ThreadPool::new(threads.min(files.len().max(1)));
+
+    // Set if we stop printing early, so queued jobs don't bother searching
+    let stop = Arc::new(AtomicBool::new(false));
+    let (sender, receiver) = mpsc::channel();
+
+    for (index, file) in files.into_iter().enumerate() {
+        let config = Arc::clone(config);
+        let matcher = Arc::clone(matcher);
+        let stop = Arc::clone(&stop);
+        let sender = sender.clone();
+
+        pool.execute(move || {
+            if stop.load(Ordering::Relaxed) {
+                return;
+            }
+
+            let mut buffer = Printer::new(Vec::new(), &config);
+            let result = search_path(&config, &matcher, &mut buffer, &file, true).map(|()| buffer);
+            // The receiver is only gone if printing already failed
+            let _ = sender.send((index, file, result));
+        });
+    }
+    drop(sender);
+
+    let mut pending = BTreeMap::new();
+    let mut next = 0;
+    let result = receiver.iter().try_for_each(|(index, file, result)| {
+        pending.insert(index, (file, result));
+
+        while let Some((file, result)) = pending.remove(&next) {
+            match result {
+                Ok(buffer) => printer.append(buffer)?,
+                Err(err) => eprintln!("minigrep: {}: {}", file.display(), err),
+            }
+            next += 1;
+        }
+        Ok(())
+    });
+
+    if result.is_err() {
+        stop.store(true, Ordering::Relaxed);
+    }
+    result
+}
+
+// Searches one file, or rewrites it with --in-place or --dry-run
+fn search_path<W: Write>(
+    config: &Config,
+    matcher: &Matcher,
+    printer: &mut Printer<W>,
+    path: &Path,
+    show_path: bool,
+) -> io::Result<()> {
+    if config.in_place || config.dry_run {
+        rewrite::file(config, matcher, printer, path)
+    } else {
+        let input = Input::open(path, config.search_zip, config.encoding, config.mmap)?;
+        search_input(config, matcher, printer, path, show_path, input)
+    }
+}
+
+// Searches one input and prints the result in the form the flags ask for.
+// show_path says whether lines should be prefixed with the file name.
+fn search_input<W: Write>(
+    config: &Config,
+    matcher: &Matcher,
+    printer: &mut Printer<W>,
+    path: &Path,
+    show_path: bool,
+    mut input: Input,
+) -> io::Result<()> {
+    let binary = !config.text && input.is_binary()?;
+    let mut sink = sink::for_config(config, matcher, printer, path, show_path, binary);
+    feed(config, matcher, input, sink.as_mut())
+}
+
+// Runs matcher over the lines of input and hands each one to sink, along
+// with whether it's selected, until the input ends or the sink or
+// --max-count says to stop. Shared by the command line and by Searcher.
+fn feed(config: &Config, matcher: &Matcher, input: Input, sink: &mut dyn Sink) -> io::Result<()> {
+    let mut limit = MaxCount::new(config);
+    let mut take = |m: &Line, selected: bool, parts: Option<&[Range<usize>]>| limit.take(sink, m, selected, parts);
+
+    if config.multiline {
+        // With -U the whole input is searched at once, and every line a
+        // match touches is selected. Those lines are still printed (and
+        // highlighted) one at a time.
+        let contents = input.into_text()?;
+        let spans = matcher.find_iter(&contents);
+        for (m, parts) in scan::lines_spanned(&spans, &contents) {
+            let more = if parts.is_empty() || config.invert_match {
+                take(&m, parts.is_empty() == config.invert_match, None)?
+            } else {
+                take(&m, true, Some(&parts))?
+            };
+            if !more {
+                break;
+            }
+        }
+    } else if !config.fields.is_empty() {
+        // Every line goes through the records, which need the header first
+        let mut records = fields::Records::new(config);
+        input.for_each_line(|m| {
+            let selected = records.select(matcher, m.line).is_some_and(|found| found != config.invert_match);
+            take(m, selected, None)
+        })?;
+    } else {
+        let on_line = |m: &Line| take(m, matcher.is_match(m.line) != config.invert_match, None);
+
+        // When nothing but the matching lines will be printed, the lines in
+        // between don't need to be looked at at all
+        let only_matches = !config.invert_match && config.after_context == 0 && config.before_context == 0;
+        match matcher.finder() {
+            Some(finder) if only_matches => input.for_each_line_containing(finder, on_line)?,
+            _ => input.for_each_line(on_line)?,
+        }
+    }
+
+    sink.finish()
+}
+
+// --max-count, between the search and the sink. Once that many lines are
+// selected, no more are, but the -A lines after the last of them still go
+// to the sink as context, like grep does; then the search stops.
+struct MaxCount {
+    max: Option<usize>,
+    after_context: usize,
+    selected: usize,
+    // Lines of context still owed to the last selected line
+    after_left: usize,
+}
+
+impl MaxCount {
+    fn new(config: &Config) -> MaxCount {
+        MaxCount { max: config.max_count, after_context: config.after_context, selected: 0, after_left: 0 }
+    }
+
+    // Hands m to sink, and says whether to go on. parts, with -U, are where
+    // the matches that selected the line are.
+    fn take(
+        &mut self,
+        sink: &mut dyn Sink,
+        m: &Line,
+        selected: bool,
+        parts: Option<&[Range<usize>]>,
+    ) -> io::Result<bool> {
+        if self.max == Some(self.selected) {
+            // Only with -m 0 is nothing owed when the limit is first seen
+            if self.after_left == 0 {
+                return Ok(false);
+            }
+            self.after_left -= 1;
+            return Ok(sink.line(m, false)? && self.after_left > 0);
+        }
+
+        let more = match parts {
+            Some(parts) => sink.spanned(m, parts)?,
+            None => sink.line(m, selected)?,
+        };
+        if selected {
+            self.selected += 1;
+            self.after_left = self.after_context;
+            if self.max == Some(self.selected) && self.after_left == 0 {
+                return Ok(false);
+            }
+        }
+        Ok(more)
+    }
+}
+
+// Config::from_args for tests, which mustn't depend on IGNORE_CASE or
+// MINIGREP_CONFIG where they run either
+#[cfg(test)]
+fn parse_args(args: &[&str]) -> Result<Config, ArgsError> {
+    Config::from_args(args.iter().map(|s| s.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines_of<'a>(matches: Vec<Line<'a>>) -> Vec<&'a str> {
+        matches.iter().map(|m| m.line).collect()
+    }
+
+    // What run would print for input, given the flags in args
+    fn output(args: &[&str], input: Input) -> String {
+        let config = parse_args(args).unwrap();
+        let matcher = Matcher::new(&config).unwrap();
+
+        let mut out = Vec::new();
+        let mut printer = Printer::new(&mut out, &config);
+        search_input(&config, &matcher, &mut printer, Path::new("poem.txt"), false, input).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn case_sensitive() {
+        let query = "duct";
+        // Note that the backslash after the opening double quote tells 
+        // Rust not to put a newline character at the beginning of the 
+        // contents of this string literal)
+        let contents = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Duct tape.";
+
+        assert_eq!(vec!["safe, fast, productive."], lines_of(search(query, contents)));
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let query = "rUsT";
+        let contents = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Trust me.";
+
+        assert_eq!(
+            vec!["Rust:", "Trust me."],
+            lines_of(search_case_insensitive(query, contents))
+        );
+    }
+
+    #[test]
+    fn case_insensitive_uses_case_folding() {
+        let contents = "\
+Grüße aus der Straße
+GRÜSSE AUS DER STRASSE
+ΟΔΟΣ
+odos";
+
+        assert_eq!(
+            vec!["Grüße aus der Straße", "GRÜSSE AUS DER STRASSE"],
+            lines_of(search_case_insensitive("strasse", contents))
+        );
+        assert_eq!(vec!["ΟΔΟΣ"], lines_of(search_case_insensitive("οδος", contents)));
+
+        // Several literals fold case the same way; a regex only maps each
+        // character to its other cases, which for ß is ẞ
+        let contents = Input::Text("Die Straße\n".to_string());
+        assert_eq!("Die Straße\n", output(&["-i", "-e", "strasse", "-e", "zzz"], contents));
+        let contents = Input::Text("Die Straße\n".to_string());
+        assert_eq!("", output(&["-iE", "strasse"], contents));
+    }
+
+    #[test]
+    fn smart_case_depends_on_the_pattern() {
+        let build = |args: &[&str]| parse_args(args).unwrap();
+
+        assert!(build(&["-S", "rust", "poem.txt"]).ignore_case);
+        assert!(!build(&["-S", "Rust", "poem.txt"]).ignore_case);
+        assert!(build(&["-S", "-E", r"\Wrust\S", "poem.txt"]).ignore_case);
+        assert!(build(&["-S", "-i", "Rust", "poem.txt"]).ignore_case);
+    }
+
+    #[test]
+    fn regex_anchors_and_classes() {
+        let re = Regex::new(r"^[A-Z]\w+ \w+\.$").unwrap();
+        let contents = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Duct tape.";
+
+        assert_eq!(vec!["Pick three.", "Duct tape."], lines_of(search_regex(&re, contents)));
+    }
+
+    #[test]
+    fn regex_alternation_and_groups() {
+        let re = Regex::new(r"(Pick|Duct) (three|tape)").unwrap();
+        let contents = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Duct tape.";
+
+        assert_eq!(vec!["Pick three.", "Duct tape."], lines_of(search_regex(&re, contents)));
+    }
+
+    #[test]
+    fn regex_flag_compiles_pattern() {
+        let config = parse_args(&["-E", "^to", "poem.txt"]).unwrap();
+
+        assert_eq!(vec!["^to"], config.patterns);
+        assert_eq!("poem.txt", config.file_path);
+        assert!(config.regex.unwrap().is_match("to be"));
+    }
+
+    #[test]
+    fn invalid_regex_is_an_error() {
+        assert!(parse_args(&["--regex", "(unclosed", "poem.txt"]).is_err());
+    }
+
+    #[test]
+    fn matches_carry_line_number_and_byte_offset() {
+        let contents = "Rust:\r\nsafe, fast, productive.\nPick three.\n";
+
+        assert_eq!(
+            vec![Line { line_number: 2, byte_offset: 7, line: "safe, fast, productive." }],
+            search("fast", contents)
+        );
+    }
+
+    #[test]
+    fn context_flags_take_a_length() {
+        let config = parse_args(&["-n", "-C", "2", "-A", "1", "to", "poem.txt"]).unwrap();
+
+        assert!(config.line_number);
+        assert_eq!((1, 2), (config.after_context, config.before_context));
+
+        assert!(parse_args(&["-B", "x", "to", "poem.txt"]).is_err());
+    }
+
+    #[test]
+    fn invert_match_selects_the_other_lines() {
+        let contents = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Duct tape.";
+
+        assert_eq!(
+            "Rust:\nsafe, fast, productive.\n",
+            output(&["-v", "-e", "Pick", "-e", "Duct"], Input::Text(contents.to_string()))
+        );
+    }
+
+    #[test]
+    fn word_regexp_skips_partial_words() {
+        let contents = "\
+safe, fast, productive.
+duct tape.";
+
+        assert_eq!("duct tape.\n", output(&["-w", "duct"], Input::Text(contents.to_string())));
+        assert_eq!("duct tape.\n", output(&["-iw", "DUCT"], Input::Text(contents.to_string())));
+        assert_eq!("", output(&["-w", "-e", "duc", "-e", "tap"], Input::Text(contents.to_string())));
+
+        // One pattern or several, a word is bounded the same way, even
+        // where it starts or ends with something that isn't a word character
+        let call = "foo( x
+foo(x)
+";
+        assert_eq!("foo( x
+", output(&["-w", "foo("], Input::Text(call.to_string())));
+        assert_eq!("foo( x
+", output(&["-w", "-e", "foo(", "-e", "zzz"], Input::Text(call.to_string())));
+        assert_eq!("foo(x)
+", output(&["-x", "-e", "foo(x)", "-e", "zzz"], Input::Text(call.to_string())));
+    }
+
+    #[test]
+    fn multiline_prints_every_line_a_match_spans() {
+        let contents = "\
+fn main() {
+    println!(
+        \"hello\"
+    );
+}";
+
+        assert_eq!(
+            "2:    println!(\n3:        \"hello\"\n",
+            output(&["-nUE", r#"println!\(\s+"hello"#], Input::Text(contents.to_string()))
+        );
+        assert_eq!(
+            "fn main() {\n    println!(\n}\n",
+            output(&["-UvE", r"^ .*\n.*\)"], Input::Text(contents.to_string()))
+        );
+        assert_eq!("2\n", output(&["-Uc", "{\n    print"], Input::Stream(Box::new(contents.as_bytes()))));
+        // Without -U no single line has a line break in it
+        assert_eq!("", output(&["-E", r#"\(\s+""#], Input::Text(contents.to_string())));
+        // Each line gets the part of the match that is on it highlighted
+        assert_eq!(
+            "fo\x1b[1;31mo\x1b[0m\n\x1b[1;31mb\x1b[0mar\n",
+            output(&["--color=always", "-U", "o\nb"], Input::Text("foo\nbar\nbaz".to_string()))
+        );
+        // A line ends before its \r\n, as it does without -U
+        let crlf = "ab\r\ncd\r\n";
+        assert_eq!("ab\n", output(&["-UE", "b$"], Input::Text(crlf.to_string())));
+        assert_eq!("cd\n", output(&["-Ux", "cd"], Input::Text(crlf.to_string())));
+    }
+
+    #[test]
+    fn line_regexp_matches_entire_lines() {
+        let contents = "\
+Pick three.
+Pick three. Or four.
+pick three.";
+
+        assert_eq!("Pick three.\n", output(&["-x", "Pick three."], Input::Text(contents.to_string())));
+        assert_eq!(
+            "Pick three.\npick three.\n",
+            output(&["-ix", "PICK THREE."], Input::Text(contents.to_string()))
+        );
+        assert_eq!(
+            "Pick three.\n",
+            output(&["-x", "-e", "Pick", "-e", "Pick three."], Input::Text(contents.to_string()))
+        );
+    }
+
+    #[test]
+    fn fields_keep_the_line_numbers_of_the_file() {
+        let contents = "level,message\nINFO,disk ok\nERROR,disk full\n\nERROR,timeout\n";
+
+        assert_eq!(
+            "3:ERROR,disk full\n",
+            output(&["-n", "--field", "level=ERROR", "--field", "message", "disk"], Input::Text(contents.to_string()))
+        );
+        // -v selects the other records, but never the header
+        assert_eq!(
+            "2:INFO,disk ok\n",
+            output(&["-nv", "--field", "level=ERROR"], Input::Text(contents.to_string()))
+        );
+    }
+
+    #[test]
+    fn counts_and_file_names_from_a_stream() {
+        let contents: &'static [u8] = b"Rust:\nTrust me.\n\xff rust\n";
+
+        assert_eq!("3\n", output(&["-ic", "rust"], Input::Stream(Box::new(contents))));
+        assert_eq!("poem.txt\n", output(&["-l", "Trust"], Input::Stream(Box::new(contents))));
+        assert_eq!("", output(&["-l", "nothing"], Input::Stream(Box::new(contents))));
+    }
+
+    #[test]
+    fn parallel_output_keeps_file_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut files = Vec::new();
+        for i in 0..20 {
+            let file = dir.path().join(format!("{:02}.txt", i));
+            // Make the early files slower to search than the later ones
+            std::fs::write(&file, "filler\n".repeat(2000 * (20 - i)) + "needle\n").unwrap();
+            files.push(file);
+        }
+
+        let config = Arc::new(parse_args(&["-C", "1", "-j", "4", "needle"]).unwrap());
+        let matcher = Arc::new(Matcher::new(&config).unwrap());
+        let mut out = Vec::new();
+        let mut printer = Printer::new(&mut out, &config);
+        search_files(&config, &matcher, &mut printer, files.clone()).unwrap();
+
+        let expected: Vec<String> = files
+            .iter()
+            .map(|f| format!("{0}-filler\n{0}:needle\n", f.display()))
+            .collect();
+        assert_eq!(expected.join("--\n"), String::from_utf8(out).unwrap());
+    }
+}
@@ -4,7 +4,7 @@ use std::env;
 use std::process;
 
 
-use minigrep::Config;
+use minigrep::{ArgsError, Config};
 
 
 fn main() {
@@ -28,14 +28,19 @@ fn main() {
     // to our closure in the argument err that appears between the vertical pipes.
     //  The code in the closure can then use the err value when it runs.
     let config = Config::build(env::args()).unwrap_or_else(|err| {
+        // --help and --version stop the search too, but they were asked for,
+        // so their text goes to standard output and we exit successfully
+        if let ArgsError::Help | ArgsError::Version = err {
+            println!("{}", err);
+            process::exit(0);
+        }
+
         // The standard library provides the eprintln! macro that prints to the standard error stream
         eprintln!("Problem parsing arguments: {}", err);
+        eprintln!("Try 'minigrep --help' for more information.");
         process::exit(1);
     });
 
-    println!("Searching for {}", config.query);
-    println!("In file {}", config.file_path);
-
     // We use if let rather than unwrap_or_else to check whether run returns an Err 
     // value and call process::exit(1) if it does. The run function doesn’t return a 
     // value that we want to unwrap in the same way that Config::build returns the Config 
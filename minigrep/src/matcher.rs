@@ -0,0 +1,405 @@
+use std::cmp::Reverse;
+use std::ops::Range;
+
+use aho_corasick::{AhoCorasick, MatchKind};
+use memchr::memmem::Finder;
+use regex::{Regex, RegexSet};
+
+use crate::fold::{self, Folded, FoldedLiteral};
+use crate::fuzzy::{self, Fuzzy};
+use crate::{ArgsError, Config};
+
+// Where a match was in a line, and where its replacement ended up in the
+// rewritten line
+pub type Replaced = (Range<usize>, Range<usize>);
+
+// The patterns from a Config, prepared once so that testing a line does
+// no more work than it has to. Used wherever lines arrive one at a time
+// instead of as one big string. How the patterns are looked for is up to
+// the Matcher; callers only ask it questions about lines.
+pub struct Matcher(Kind);
+
+enum Kind {
+    // Substring search with memchr's memmem, which is what str::contains
+    // does too, minus rebuilding the searcher for every line
+    Literal(Box<Finder<'static>>),
+    CaseInsensitive(FoldedLiteral),
+    // Any number of literal patterns, found in one pass over the line by an
+    // Aho-Corasick automaton. Each match knows which pattern it was.
+    Multi(AhoCorasick),
+    Regex(Regex),
+    // A regex made from several patterns, and the same patterns as a set
+    // to tell which of them matched (--show-pattern)
+    RegexSet(Regex, RegexSet),
+    // Literals that need more than comparing bytes: several with -i, or
+    // any number with -w or -x. Still found in one pass (see Literals).
+    Literals(Literals),
+    // --fuzzy: a single literal, give or take a few edits
+    Fuzzy(Fuzzy),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Boundary {
+    Word,
+    Line,
+}
+
+impl Boundary {
+    // Whether found stands on its own in text. A word is bounded by
+    // characters that can't be part of one, as in grep -w: Unicode letters,
+    // digits and the underscore are word characters, so "café" is a word
+    // and "duct" isn't one inside "productive".
+    fn fits(self, text: &str, found: &Range<usize>) -> bool {
+        match self {
+            Boundary::Word => {
+                let is_word = |c: char| c.is_alphanumeric() || c == '_';
+                let before = text[..found.start].chars().next_back();
+                let after = text[found.end..].chars().next();
+                !before.is_some_and(is_word) && !after.is_some_and(is_word)
+            }
+            Boundary::Line => found.start == 0 && found.end == text.len(),
+        }
+    }
+}
+
+// Literal patterns found by one Aho-Corasick automaton, where not every
+// hit is a match. With -i the automaton holds the patterns' full case
+// folds and runs over the folded line, and a hit that starts or ends
+// partway through the fold of a character doesn't count. With -w or -x, a
+// hit has to take up a whole word or the whole line. So every hit is
+// reported, overlapping ones too, and one that doesn't count can't hide
+// one that does; the matches are the leftmost-longest of the rest.
+struct Literals {
+    ac: AhoCorasick,
+    ignore_case: bool,
+    boundary: Option<Boundary>,
+}
+
+impl Literals {
+    fn new(patterns: &[String], ignore_case: bool, boundary: Option<Boundary>) -> Literals {
+        let patterns: Vec<String> =
+            if ignore_case { patterns.iter().map(|p| fold::fold(p)).collect() } else { patterns.to_vec() };
+        let ac = AhoCorasick::builder()
+            .match_kind(MatchKind::Standard)
+            .build(&patterns)
+            .expect("literal patterns always build an automaton");
+        Literals { ac, ignore_case, boundary }
+    }
+
+    // Every hit in text that counts, with the index of its pattern, in the
+    // order they end
+    fn hits(&self, text: &str) -> Vec<(Range<usize>, usize)> {
+        let folded = self.ignore_case.then(|| Folded::new(text));
+        let haystack = folded.as_ref().map_or(text, |folded| folded.text.as_str());
+        self.ac
+            .find_overlapping_iter(haystack)
+            .filter_map(|hit| {
+                let range = match &folded {
+                    Some(folded) => folded.original(hit.range())?,
+                    None => hit.range(),
+                };
+                let fits = self.boundary.is_none_or(|boundary| boundary.fits(text, &range));
+                fits.then_some((range, hit.pattern().as_usize()))
+            })
+            .collect()
+    }
+
+    // The non-empty hits that don't overlap, leftmost first and longest
+    // of those that start at the same place
+    fn matches(&self, text: &str) -> Vec<Range<usize>> {
+        let mut hits: Vec<Range<usize>> =
+            self.hits(text).into_iter().map(|(range, _)| range).filter(|range| !range.is_empty()).collect();
+        hits.sort_by_key(|range| (range.start, Reverse(range.end)));
+
+        let mut found: Vec<Range<usize>> = Vec::new();
+        for hit in hits {
+            if found.last().is_none_or(|last| hit.start >= last.end) {
+                found.push(hit);
+            }
+        }
+        found
+    }
+}
+
+// What Matcher::new needs of a Config beyond what its types say, checked
+// there for Configs built by hand and by cli::finish for the command line.
+// Fuzzy matching is its own kind of search, for one plain string.
+pub(crate) fn check(config: &Config) -> Result<(), ArgsError> {
+    if let Some(max_edits) = config.fuzzy {
+        let single = config.patterns.len() == 1 && config.regex.is_none();
+        if !single || config.word_regexp || config.line_regexp {
+            return Err(ArgsError::Requires { flag: "--fuzzy", needs: "a single literal pattern, without -w or -x" });
+        }
+        if config.patterns[0].chars().count() > fuzzy::MAX_LEN {
+            return Err(ArgsError::TooLong { flag: "--fuzzy", max: fuzzy::MAX_LEN });
+        }
+        // With as many edits as the pattern has characters, every line,
+        // even an empty one, would match
+        if max_edits >= config.patterns[0].chars().count() {
+            return Err(ArgsError::Requires { flag: "--fuzzy", needs: "fewer edits than the pattern has characters" });
+        }
+    }
+    Ok(())
+}
+
+impl Matcher {
+    pub fn new(config: &Config) -> Result<Matcher, ArgsError> {
+        check(config)?;
+        if let Some(max_edits) = config.fuzzy {
+            let fuzzy = Fuzzy::new(&config.patterns[0], max_edits, config.ignore_case)
+                .ok_or(ArgsError::TooLong { flag: "--fuzzy", max: fuzzy::MAX_LEN })?;
+            return Ok(Matcher(Kind::Fuzzy(fuzzy)));
+        }
+        let boundary = if config.line_regexp {
+            Some(Boundary::Line)
+        } else if config.word_regexp {
+            Some(Boundary::Word)
+        } else {
+            None
+        };
+        let kind = match (&config.regex, &config.regex_set) {
+            (Some(re), Some(set)) => Kind::RegexSet(re.clone(), set.clone()),
+            (Some(re), None) => Kind::Regex(re.clone()),
+            (None, _) if boundary.is_some() || (config.ignore_case && config.patterns.len() > 1) => {
+                Kind::Literals(Literals::new(&config.patterns, config.ignore_case, boundary))
+            }
+            (None, _) if config.patterns.len() != 1 => Kind::Multi(
+                // Leftmost-longest, so "foobar" is highlighted whole when
+                // both foo and foobar are patterns
+                AhoCorasick::builder()
+                    .match_kind(MatchKind::LeftmostLongest)
+                    .build(&config.patterns)
+                    .expect("literal patterns always build an automaton"),
+            ),
+            (None, _) if config.ignore_case => Kind::CaseInsensitive(FoldedLiteral::new(&config.patterns[0])),
+            (None, _) => Kind::Literal(Box::new(Finder::new(&config.patterns[0]).into_owned())),
+        };
+        Ok(Matcher(kind))
+    }
+
+    pub fn is_match(&self, line: &str) -> bool {
+        match &self.0 {
+            Kind::Literal(finder) => finder.find(line.as_bytes()).is_some(),
+            Kind::CaseInsensitive(query) => query.is_match(line),
+            Kind::Multi(ac) => ac.is_match(line),
+            Kind::Regex(re) | Kind::RegexSet(re, _) => re.is_match(line),
+            Kind::Literals(literals) => !literals.hits(line).is_empty(),
+            Kind::Fuzzy(query) => query.is_match(line),
+        }
+    }
+
+    // --fuzzy: how many edits the closest match in line is from the query
+    pub fn distance(&self, line: &str) -> Option<usize> {
+        match &self.0 {
+            Kind::Fuzzy(query) => query.distance(line),
+            _ => None,
+        }
+    }
+
+    // The searcher for the pattern when it can be looked for across a whole
+    // buffer instead of line by line (see scan::lines_containing): a single
+    // literal that can't match across or into a line break
+    pub(crate) fn finder(&self) -> Option<&Finder<'static>> {
+        match &self.0 {
+            Kind::Literal(finder) if !finder.needle().iter().any(|b| matches!(b, b'\n' | b'\r')) => {
+                Some(finder)
+            }
+            _ => None,
+        }
+    }
+
+    // The indexes of the patterns that match text, in the order they
+    // first match
+    pub fn patterns_in(&self, text: &str) -> Vec<usize> {
+        match &self.0 {
+            Kind::Literal(_) | Kind::CaseInsensitive(_) | Kind::Regex(_) | Kind::Fuzzy(_) => {
+                if self.is_match(text) { vec![0] } else { Vec::new() }
+            }
+            Kind::Multi(ac) => {
+                let mut found = Vec::new();
+                for m in ac.find_iter(text) {
+                    if !found.contains(&m.pattern().as_usize()) {
+                        found.push(m.pattern().as_usize());
+                    }
+                }
+                found
+            }
+            Kind::RegexSet(_, set) => set.matches(text).into_iter().collect(),
+            Kind::Literals(literals) => {
+                let mut hits: Vec<(usize, usize)> =
+                    literals.hits(text).into_iter().map(|(range, i)| (range.start, i)).collect();
+                hits.sort();
+                let mut found = Vec::new();
+                for (_, i) in hits {
+                    if !found.contains(&i) {
+                        found.push(i);
+                    }
+                }
+                found
+            }
+        }
+    }
+
+    // The byte ranges of every non-overlapping match in line
+    pub fn find_iter(&self, line: &str) -> Vec<Range<usize>> {
+        match &self.0 {
+            Kind::Literal(finder) if finder.needle().is_empty() => Vec::new(),
+            Kind::Literal(finder) => finder
+                .find_iter(line.as_bytes())
+                .map(|start| start..start + finder.needle().len())
+                .collect(),
+            Kind::CaseInsensitive(query) => query.find_iter(line).collect(),
+            Kind::Literals(literals) => literals.matches(line),
+            Kind::Multi(ac) => ac
+                .find_iter(line)
+                .filter(|m| !m.is_empty())
+                .map(|m| m.range())
+                .collect(),
+            Kind::Regex(re) | Kind::RegexSet(re, _) => re
+                .find_iter(line)
+                .filter(|m| !m.is_empty())
+                .map(|m| m.range())
+                .collect(),
+            Kind::Fuzzy(query) => query.find_iter(line),
+        }
+    }
+
+    // line with every match swapped for replacement (--replace), along
+    // with the ranges of each match before and after. A Regex expands $1
+    // or ${name} in replacement to what that group captured; the literal
+    // matchers insert it as is.
+    pub fn replace(&self, line: &str, replacement: &str) -> (String, Vec<Replaced>) {
+        let mut out = String::with_capacity(line.len());
+        let mut ranges = Vec::new();
+        let mut last = 0;
+
+        let mut push = |out: &mut String, from: Range<usize>, expand: &dyn Fn(&mut String)| {
+            out.push_str(&line[last..from.start]);
+            let start = out.len();
+            expand(out);
+            last = from.end;
+            ranges.push((from, start..out.len()));
+        };
+
+        match &self.0 {
+            Kind::Regex(re) | Kind::RegexSet(re, _) => {
+                for caps in re.captures_iter(line) {
+                    let whole = caps.get(0).unwrap();
+                    // Empty matches aren't shown by find_iter either
+                    if !whole.is_empty() {
+                        push(&mut out, whole.range(), &|out| caps.expand(replacement, out));
+                    }
+                }
+            }
+            _ => {
+                for from in self.find_iter(line) {
+                    push(&mut out, from, &|out| out.push_str(replacement));
+                }
+            }
+        }
+
+        out.push_str(&line[last..]);
+        (out, ranges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multi_reports_which_patterns_matched() {
+        let patterns = ["foo", "foobar", "baz", ""].map(String::from);
+        let config = Config { patterns: patterns[..3].to_vec(), ..Config::default() };
+        let matcher = Matcher::new(&config).unwrap();
+
+        assert!(matcher.is_match("a baz"));
+        assert_eq!(vec![0..6, 7..10, 11..14], matcher.find_iter("foobar baz foo"));
+        assert_eq!(vec![1, 2, 0], matcher.patterns_in("foobar baz foo"));
+        assert!(matcher.patterns_in("qux").is_empty());
+
+        // No patterns at all (-f with an empty file) match nothing
+        let matcher = Matcher::new(&Config::default()).unwrap();
+        assert!(!matcher.is_match("anything"));
+
+        let config = Config { patterns: patterns.to_vec(), ..Config::default() };
+        assert!(Matcher::new(&config).unwrap().is_match("qux"));
+    }
+
+    #[test]
+    fn several_folded_literals_match_leftmost_then_longest() {
+        let patterns = ["STRASSE", "straße in", "grüsse"].map(String::from).to_vec();
+        let matcher = Matcher::new(&Config { patterns, ignore_case: true, ..Config::default() }).unwrap();
+
+        let line = "Grüße aus der Straße in Berlin";
+        assert_eq!(vec![0..7, 16..26], matcher.find_iter(line));
+        assert_eq!(vec![2, 0, 1], matcher.patterns_in(line));
+        assert!(!matcher.is_match("Strasbourg"));
+
+        // "as" ends halfway through ß, and mustn't keep "sse" from matching
+        let patterns = ["AS", "SSE", ""].map(String::from).to_vec();
+        let matcher = Matcher::new(&Config { patterns, ignore_case: true, ..Config::default() }).unwrap();
+        assert_eq!(vec![4..7], matcher.find_iter("Straße"));
+        assert_eq!(vec![2, 1], matcher.patterns_in("Straße"));
+        assert!(matcher.is_match("anything"));
+    }
+
+    #[test]
+    fn replace_expands_capture_groups() {
+        let re = Regex::new(r"(?P<word>\w+)@(\w+)").unwrap();
+        let (line, ranges) = Matcher(Kind::Regex(re)).replace("mail bob@home, amy@work", "$2:${word}");
+
+        assert_eq!("mail home:bob, work:amy", line);
+        assert_eq!(vec![(5..13, 5..13), (15..23, 15..23)], ranges);
+    }
+
+    #[test]
+    fn replace_literals_as_is() {
+        let (line, ranges) = Matcher(Kind::Literal(Box::new(Finder::new("ß").into_owned()))).replace("Straße", "$1ss");
+        assert_eq!("Stra$1sse", line);
+        assert_eq!(vec![(4..6, 4..8)], ranges);
+
+        let folded = Matcher(Kind::CaseInsensitive(FoldedLiteral::new("RUST")));
+        assert_eq!("TGo me, Go is Go.", folded.replace("Trust me, rust is Rust.", "Go").0);
+    }
+
+    #[test]
+    fn bounded_literals_match_whole_words_and_lines() {
+        let matcher = |flags: &[&str], patterns: &str| {
+            let config = Config {
+                patterns: patterns.split('|').map(String::from).collect(),
+                ignore_case: flags.contains(&"-i"),
+                word_regexp: flags.contains(&"-w"),
+                line_regexp: flags.contains(&"-x"),
+                ..Config::default()
+            };
+            Matcher::new(&config).unwrap()
+        };
+
+        let word = matcher(&["-w"], "duct");
+        assert!(!word.is_match("safe, fast, productive."));
+        assert!(word.is_match("duct tape."));
+        assert!(!word.is_match("duct_tape"));
+        assert!(!matcher(&["-w"], "caf").is_match("café au lait"));
+        assert_eq!(vec![4..6], matcher(&["-w"], "aa").find_iter("aaa aa"));
+        assert!(matcher(&["-w"], "a").find_iter(&"a".repeat(100_000)).is_empty());
+        // Several patterns in one pass: "a b" isn't a word, and mustn't
+        // keep "b c" from being one
+        let several = matcher(&["-w"], "a b|b c");
+        assert_eq!(vec![3..6], several.find_iter("xa b c"));
+        assert_eq!(vec![1], several.patterns_in("xa b c"));
+
+        let folded = matcher(&["-i", "-w"], "STRASSE");
+        assert_eq!(vec![4..11], folded.find_iter("Die Straße, Straßenbahn"));
+
+        let line = matcher(&["-x"], "Pick three.");
+        assert!(line.is_match("Pick three."));
+        assert!(!line.is_match("Pick three. Or four."));
+        assert!(matcher(&["-i", "-x"], "pick THREE.").is_match("Pick three."));
+        assert!(matcher(&["-i", "-x"], "pick|pick THREE.").is_match("Pick three."));
+        assert!(matcher(&["-x"], "").is_match(""));
+        assert!(!matcher(&["-x"], "").is_match("text"));
+
+        assert!(word.finder().is_none());
+    }
+}
@@ -0,0 +1,189 @@
+use std::fs::File;
+
+use memmap2::Mmap;
+
+// A read-only memory map of a whole file, so a big file can be searched
+// without first copying it into a String (see Input::open).
+//
+// Mapping a file has one catch: if another process truncates the file
+// while it's mapped, touching a page past the new end raises SIGBUS, which
+// would kill minigrep. On Linux, maps are registered with a SIGBUS handler
+// that puts a page of zeros where the missing data was and notes that the
+// file was truncated, so the search can finish and report it. Windows
+// doesn't allow truncating a file that is mapped, and elsewhere files
+// aren't mapped at all.
+pub struct Mapped {
+    // Declared before map so that it's released first: once the map is
+    // gone, its address range may be reused by another thread's map
+    #[cfg(target_os = "linux")]
+    guard: guard::Guard,
+    map: Mmap,
+}
+
+impl Mapped {
+    // Maps file, or returns None when it can't be, in which case it should
+    // be read instead
+    pub fn new(file: &File) -> Option<Mapped> {
+        #[cfg(target_os = "linux")]
+        {
+            let guard = guard::Guard::reserve()?;
+            // SAFETY: the map is only ever read as bytes, which are copied
+            // out before being interpreted as text, so the file changing
+            // underneath can garble lines but not break any invariant.
+            // Truncation is caught by the guard.
+            let map = unsafe { Mmap::map(file) }.ok()?;
+            guard.watch(map.as_ptr() as usize, map.len());
+            Some(Mapped { guard, map })
+        }
+        #[cfg(windows)]
+        {
+            // SAFETY: as above; Windows refuses to truncate a mapped file
+            let map = unsafe { Mmap::map(file) }.ok()?;
+            Some(Mapped { map })
+        }
+        #[cfg(not(any(target_os = "linux", windows)))]
+        {
+            let _ = file;
+            None
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.map
+    }
+
+    // Whether part of the file disappeared while it was being read. The
+    // missing part reads as zeros.
+    pub fn truncated(&self) -> bool {
+        #[cfg(target_os = "linux")]
+        return self.guard.tripped();
+        #[cfg(not(target_os = "linux"))]
+        return false;
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod guard {
+    use std::ptr;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Once, OnceLock};
+
+    use libc::{c_int, c_void, siginfo_t};
+
+    // How many files can be mapped at once; when every slot is taken, the
+    // next file is read instead. Workers map one file at a time each.
+    const SLOTS: usize = 64;
+
+    // One mapped range the SIGBUS handler looks after. A signal handler
+    // can't take locks, so the table is a fixed array of atomics.
+    struct Slot {
+        used: AtomicBool,
+        start: AtomicUsize,
+        len: AtomicUsize,
+        tripped: AtomicBool,
+    }
+
+    impl Slot {
+        const fn new() -> Slot {
+            Slot {
+                used: AtomicBool::new(false),
+                start: AtomicUsize::new(0),
+                len: AtomicUsize::new(0),
+                tripped: AtomicBool::new(false),
+            }
+        }
+    }
+
+    static TABLE: [Slot; SLOTS] = [const { Slot::new() }; SLOTS];
+
+    static INSTALL: Once = Once::new();
+    static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+    // Whatever handled SIGBUS before us, for faults that aren't ours
+    static PREVIOUS: OnceLock<libc::sigaction> = OnceLock::new();
+
+    pub struct Guard(&'static Slot);
+
+    impl Guard {
+        pub fn reserve() -> Option<Guard> {
+            INSTALL.call_once(install);
+            TABLE
+                .iter()
+                .find(|slot| slot.used.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok())
+                .map(Guard)
+        }
+
+        // Starts looking after the len bytes at start
+        pub fn watch(&self, start: usize, len: usize) {
+            self.0.tripped.store(false, Ordering::Relaxed);
+            self.0.len.store(len, Ordering::Relaxed);
+            self.0.start.store(start, Ordering::Release);
+        }
+
+        pub fn tripped(&self) -> bool {
+            self.0.tripped.load(Ordering::Acquire)
+        }
+    }
+
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            self.0.start.store(0, Ordering::Release);
+            self.0.used.store(false, Ordering::Release);
+        }
+    }
+
+    fn install() {
+        // SAFETY: plain libc calls with valid arguments; a zeroed sigaction
+        // is a valid starting point
+        unsafe {
+            PAGE_SIZE.store(libc::sysconf(libc::_SC_PAGESIZE) as usize, Ordering::Relaxed);
+
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_sigbus as *const () as usize;
+            action.sa_flags = libc::SA_SIGINFO;
+            libc::sigemptyset(&mut action.sa_mask);
+            let mut previous: libc::sigaction = std::mem::zeroed();
+            if libc::sigaction(libc::SIGBUS, &action, &mut previous) == 0 {
+                let _ = PREVIOUS.set(previous);
+            }
+        }
+    }
+
+    extern "C" fn on_sigbus(_: c_int, info: *mut siginfo_t, _: *mut c_void) {
+        // SAFETY: the kernel passes a valid siginfo_t with SA_SIGINFO
+        let addr = unsafe { (*info).si_addr() } as usize;
+        let page_size = PAGE_SIZE.load(Ordering::Relaxed);
+
+        for slot in &TABLE {
+            let start = slot.start.load(Ordering::Acquire);
+            if start == 0 || addr < start || addr >= start + slot.len.load(Ordering::Relaxed) {
+                continue;
+            }
+            // Replace the page that's gone with zeros; the faulting read
+            // is retried when the handler returns
+            let page = addr & !(page_size - 1);
+            // SAFETY: the page is part of a map we own, which stays mapped
+            // until its Guard has been dropped
+            let zeros = unsafe {
+                libc::mmap(
+                    page as *mut c_void,
+                    page_size,
+                    libc::PROT_READ,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED,
+                    -1,
+                    0,
+                )
+            };
+            if zeros != libc::MAP_FAILED {
+                slot.tripped.store(true, Ordering::Release);
+                return;
+            }
+        }
+
+        // Not one of ours: hand SIGBUS back to whoever had it, and the
+        // retried read gets the treatment it would have got without us
+        if let Some(previous) = PREVIOUS.get() {
+            // SAFETY: previous came from sigaction itself
+            unsafe { libc::sigaction(libc::SIGBUS, previous, ptr::null_mut()) };
+        }
+    }
+}
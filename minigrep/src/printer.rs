@@ -0,0 +1,528 @@
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::time::Duration;
+
+use serde_json::json;
+
+use crate::rewrite::Change;
+use crate::{ColorChoice, Config, Line};
+
+// SGR codes for each part of the output, the same defaults GNU grep uses
+const MATCH_COLOR: &str = "1;31";
+const PATH_COLOR: &str = "35";
+const NUMBER_COLOR: &str = "32";
+const SEPARATOR_COLOR: &str = "36";
+// --show-pattern has no GNU grep equivalent
+const PATTERN_COLOR: &str = "33";
+// and the ones git diff uses
+const DIFF_HEADER_COLOR: &str = "1";
+const REMOVED_COLOR: &str = "31";
+const ADDED_COLOR: &str = "32";
+
+// Writes search results in grep's format: an optional file name, line
+// number, byte offset and notes (the matched patterns, the fuzzy distance)
+// in front of each line, separated by ':' on matching lines and by '-' on
+// context lines. Non-adjacent groups of context are separated by a "--"
+// line.
+//
+// Lines are fed in one at a time, so only the last -B lines have to be
+// kept around, however big the input is.
+//
+// With --json every line is written as a JSON object instead, one per
+// line of output:
+//   {"type":"begin","data":{"path":...}} before a file's first line
+//   {"type":"match" or "context","data":{"path","line_number","column",
+//       "absolute_offset","line","submatches":[{"match","start","end"}],
+//       "patterns" with --show-pattern, "distance" with --fuzzy}}
+//   {"type":"end","data":{"path":...,"stats":{...}}} after its last line
+//   {"type":"summary","data":{"stats":{...},"elapsed_secs":...}} at the end
+// column is the 1-based byte column of the line's first match, if any.
+pub struct Printer<W> {
+    out: W,
+    line_number: bool,
+    byte_offset: bool,
+    after_context: usize,
+    before_context: usize,
+    // Whether to wrap the parts of the output in ANSI color codes
+    colors: bool,
+    // Whether to write JSON Lines events instead of text
+    json: bool,
+    // Whether the begin event for the current file has been written
+    begun: bool,
+    // Counts for the current file, and for everything printed so far
+    file_stats: Stats,
+    stats: Stats,
+    // Unselected lines that may still be needed as before-context
+    held: VecDeque<Held>,
+    // How many more lines to print as after-context
+    after_left: usize,
+    // Line number of the last line printed from the current file
+    last_printed: Option<usize>,
+    // Set once any group has been written, so the next group (in this
+    // file or a later one) gets a "--" separator in front of it
+    printed_group: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Stats {
+    searches: usize,
+    searches_with_match: usize,
+    matched_lines: usize,
+    matches: usize,
+}
+
+impl Stats {
+    fn add(&mut self, other: Stats) {
+        self.searches += other.searches;
+        self.searches_with_match += other.searches_with_match;
+        self.matched_lines += other.matched_lines;
+        self.matches += other.matches;
+    }
+}
+
+// An owned copy of a line, since the caller's buffer is reused
+struct Held {
+    line_number: usize,
+    byte_offset: usize,
+    line: String,
+}
+
+// What is said about a selected line besides where it is: the patterns it
+// matched (--show-pattern), and how many edits away from the query it is
+// (--fuzzy), written as ~1
+#[derive(Debug, Default)]
+pub struct Notes<'a> {
+    pub patterns: Vec<&'a str>,
+    pub distance: Option<usize>,
+}
+
+impl<W: Write> Printer<W> {
+    pub fn new(out: W, config: &Config) -> Printer<W> {
+        Printer {
+            out,
+            line_number: config.line_number,
+            byte_offset: config.byte_offset,
+            after_context: config.after_context,
+            before_context: config.before_context,
+            // run has already decided what Auto means for this output
+            colors: config.color == ColorChoice::Always,
+            json: config.json,
+            begun: false,
+            file_stats: Stats::default(),
+            stats: Stats::default(),
+            held: VecDeque::new(),
+            after_left: 0,
+            last_printed: None,
+            printed_group: false,
+        }
+    }
+
+    // Forgets the context of the previous file
+    pub fn begin_file(&mut self) {
+        self.held.clear();
+        self.after_left = 0;
+        self.last_printed = None;
+        self.begun = false;
+        self.file_stats = Stats::default();
+    }
+
+    // Wraps up the current file, adding its counts to the totals. In JSON
+    // mode, files that had lines printed get an end event with their counts.
+    pub fn end_file(&mut self, path: Option<&Path>) -> io::Result<()> {
+        self.file_stats.searches = 1;
+        if self.file_stats.matched_lines > 0 {
+            self.file_stats.searches_with_match = 1;
+        }
+        self.stats.add(self.file_stats);
+
+        if self.json && self.begun {
+            let event = json!({
+                "type": "end",
+                "data": {
+                    "path": path.map(|p| p.to_string_lossy()),
+                    "stats": {
+                        "matched_lines": self.file_stats.matched_lines,
+                        "matches": self.file_stats.matches,
+                    },
+                },
+            });
+            writeln!(self.out, "{}", event)?;
+        }
+        Ok(())
+    }
+
+    // The summary event that ends JSON output
+    pub fn summary(&mut self, elapsed: Duration) -> io::Result<()> {
+        let event = json!({
+            "type": "summary",
+            "data": {
+                "stats": {
+                    "searches": self.stats.searches,
+                    "searches_with_match": self.stats.searches_with_match,
+                    "matched_lines": self.stats.matched_lines,
+                    "matches": self.stats.matches,
+                },
+                "elapsed_secs": elapsed.as_secs_f64(),
+            },
+        });
+        writeln!(self.out, "{}", event)
+    }
+
+    // Whether selected lines need their matches located: for highlighting
+    // them, or for listing them as JSON submatches
+    pub fn wants_matches(&self) -> bool {
+        self.colors || self.json
+    }
+
+    // Handles the next line of the current file. selected lines are
+    // printed; the others only if they fall in some selected line's context.
+    // path is Some when the output should say which file the line came from.
+    // highlight holds the byte ranges of the line to show in the match color,
+    // and notes what else to say about it.
+    pub fn line(
+        &mut self,
+        path: Option<&Path>,
+        m: &Line,
+        selected: bool,
+        highlight: &[Range<usize>],
+        notes: &Notes,
+    ) -> io::Result<()> {
+        if selected {
+            let first = self.held.front().map_or(m.line_number, |h| h.line_number);
+            self.start_group(first)?;
+
+            while let Some(held) = self.held.pop_front() {
+                let context = Line {
+                    line_number: held.line_number,
+                    byte_offset: held.byte_offset,
+                    line: &held.line,
+                };
+                self.write_line(path, &context, '-', &[], &Notes::default())?;
+            }
+            self.write_line(path, m, ':', highlight, notes)?;
+
+            self.file_stats.matched_lines += 1;
+            self.file_stats.matches += highlight.len();
+            self.last_printed = Some(m.line_number);
+            self.after_left = self.after_context;
+        } else if self.after_left > 0 {
+            self.write_line(path, m, '-', &[], &Notes::default())?;
+
+            self.last_printed = Some(m.line_number);
+            self.after_left -= 1;
+        } else if self.before_context > 0 {
+            if self.held.len() == self.before_context {
+                self.held.pop_front();
+            }
+            self.held.push_back(Held {
+                line_number: m.line_number,
+                byte_offset: m.byte_offset,
+                line: m.line.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    // -o: one matched part of a line, on a line of its own
+    pub fn matched_part(&mut self, path: Option<&Path>, part: &Line, notes: &Notes) -> io::Result<()> {
+        let whole = 0..part.line.len();
+        self.write_line(path, part, ':', &[whole], notes)
+    }
+
+    // --dry-run: the lines --in-place would change in path, as a unified
+    // diff without context lines
+    pub fn print_diff(&mut self, path: &Path, changes: &[Change]) -> io::Result<()> {
+        self.paint(DIFF_HEADER_COLOR, format_args!("--- {}", path.display()))?;
+        writeln!(self.out)?;
+        self.paint(DIFF_HEADER_COLOR, format_args!("+++ {}", path.display()))?;
+        writeln!(self.out)?;
+
+        // How far the new line numbers have drifted from the old ones,
+        // for replacements that contain line breaks
+        let mut shift = 0;
+        for change in changes {
+            let new: Vec<&str> = change.new.split('\n').collect();
+            let new_start = change.line_number + shift;
+            let new_range = match new.len() {
+                1 => new_start.to_string(),
+                len => format!("{},{}", new_start, len),
+            };
+            self.paint(SEPARATOR_COLOR, format_args!("@@ -{} +{} @@", change.line_number, new_range))?;
+            writeln!(self.out)?;
+
+            self.paint(REMOVED_COLOR, format_args!("-{}", change.old))?;
+            writeln!(self.out)?;
+            for line in &new {
+                self.paint(ADDED_COLOR, format_args!("+{}", line))?;
+                writeln!(self.out)?;
+            }
+            shift += new.len() - 1;
+        }
+        Ok(())
+    }
+
+    // -c: the number of selected lines, after the file name if there is one
+    pub fn print_count(&mut self, path: Option<&Path>, count: usize) -> io::Result<()> {
+        if let Some(path) = path {
+            self.paint(PATH_COLOR, path.display())?;
+            self.paint(SEPARATOR_COLOR, ':')?;
+        }
+        writeln!(self.out, "{}", count)
+    }
+
+    // In place of the lines of a binary file that had selected lines; in
+    // JSON mode, as a binary event
+    pub fn print_binary_match(&mut self, path: &Path) -> io::Result<()> {
+        if self.json {
+            let event = json!({ "type": "binary", "data": { "path": path.to_string_lossy() } });
+            return writeln!(self.out, "{}", event);
+        }
+        writeln!(self.out, "Binary file {} matches", path.display())
+    }
+
+    // -l: just the name of a file that had selected lines
+    pub fn print_path(&mut self, path: &Path) -> io::Result<()> {
+        self.paint(PATH_COLOR, path.display())?;
+        writeln!(self.out)
+    }
+
+    // Writes out what another printer collected for one file, adding the
+    // "--" separator between them as if this printer had done the printing
+    pub fn append(&mut self, other: Printer<Vec<u8>>) -> io::Result<()> {
+        if self.printed_group && other.printed_group {
+            self.separator()?;
+        }
+        self.printed_group |= other.printed_group;
+        self.stats.add(other.stats);
+        self.out.write_all(&other.out)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    // Writes the "--" separator if the line about to be printed doesn't
+    // continue the previous group
+    fn start_group(&mut self, line_number: usize) -> io::Result<()> {
+        if self.after_context == 0 && self.before_context == 0 {
+            return Ok(());
+        }
+
+        let continues = self.last_printed.is_some_and(|last| last + 1 == line_number);
+        if !continues && self.printed_group {
+            self.separator()?;
+        }
+        self.printed_group = true;
+        Ok(())
+    }
+
+    fn separator(&mut self) -> io::Result<()> {
+        if self.json {
+            return Ok(());
+        }
+        self.paint(SEPARATOR_COLOR, "--")?;
+        writeln!(self.out)
+    }
+
+    fn write_line(
+        &mut self,
+        path: Option<&Path>,
+        m: &Line,
+        sep: char,
+        highlight: &[Range<usize>],
+        notes: &Notes,
+    ) -> io::Result<()> {
+        if self.json {
+            return self.write_json_line(path, m, sep, highlight, notes);
+        }
+
+        if let Some(path) = path {
+            self.paint(PATH_COLOR, path.display())?;
+            self.paint(SEPARATOR_COLOR, sep)?;
+        }
+        if self.line_number {
+            self.paint(NUMBER_COLOR, m.line_number)?;
+            self.paint(SEPARATOR_COLOR, sep)?;
+        }
+        if self.byte_offset {
+            self.paint(NUMBER_COLOR, m.byte_offset)?;
+            self.paint(SEPARATOR_COLOR, sep)?;
+        }
+        if !notes.patterns.is_empty() {
+            self.paint(PATTERN_COLOR, notes.patterns.join(","))?;
+            self.paint(SEPARATOR_COLOR, sep)?;
+        }
+        if let Some(distance) = notes.distance {
+            self.paint(NUMBER_COLOR, format_args!("~{}", distance))?;
+            self.paint(SEPARATOR_COLOR, sep)?;
+        }
+
+        let mut end = 0;
+        for range in highlight {
+            write!(self.out, "{}", &m.line[end..range.start])?;
+            self.paint(MATCH_COLOR, &m.line[range.clone()])?;
+            end = range.end;
+        }
+        writeln!(self.out, "{}", &m.line[end..])
+    }
+
+    fn write_json_line(
+        &mut self,
+        path: Option<&Path>,
+        m: &Line,
+        sep: char,
+        highlight: &[Range<usize>],
+        notes: &Notes,
+    ) -> io::Result<()> {
+        let path = path.map(|p| p.to_string_lossy());
+        if !self.begun {
+            writeln!(self.out, "{}", json!({ "type": "begin", "data": { "path": path } }))?;
+            self.begun = true;
+        }
+
+        let submatches: Vec<_> = highlight
+            .iter()
+            .map(|r| json!({ "match": &m.line[r.clone()], "start": r.start, "end": r.end }))
+            .collect();
+        let mut event = json!({
+            "type": if sep == ':' { "match" } else { "context" },
+            "data": {
+                "path": path,
+                "line_number": m.line_number,
+                "column": highlight.first().map(|r| r.start + 1),
+                "absolute_offset": m.byte_offset,
+                "line": m.line,
+                "submatches": submatches,
+            },
+        });
+        if !notes.patterns.is_empty() {
+            event["data"]["patterns"] = json!(notes.patterns);
+        }
+        if let Some(distance) = notes.distance {
+            event["data"]["distance"] = json!(distance);
+        }
+        writeln!(self.out, "{}", event)
+    }
+
+    // Writes text, wrapped in the given color when colors are on
+    fn paint(&mut self, color: &str, text: impl Display) -> io::Result<()> {
+        if self.colors {
+            write!(self.out, "\x1b[{}m{}\x1b[0m", color, text)
+        } else {
+            write!(self.out, "{}", text)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lines;
+    use crate::parse_args;
+
+    const POEM: &str = "\
+one
+two match
+three
+four
+five
+six match
+seven
+eight
+nine
+ten match";
+
+    // Prints POEM as one file, then its second half as another
+    fn render(flags: &[&str], path: Option<&Path>) -> String {
+        let config = parse_args(&[flags, &["match", "poem.txt"]].concat()).unwrap();
+
+        let mut out = Vec::new();
+        let mut printer = Printer::new(&mut out, &config);
+        for skip in [0, 5] {
+            printer.begin_file();
+            for m in lines(POEM).skip(skip) {
+                let highlight: Vec<_> = m.line.find("match").map(|i| i..i + 5).into_iter().collect();
+                printer.line(path, &m, !highlight.is_empty(), &highlight, &Notes::default()).unwrap();
+            }
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn prefixes_path_line_number_and_offset() {
+        assert_eq!(
+            "\
+poem.txt:2:4:two match
+poem.txt:6:30:six match
+poem.txt:10:57:ten match
+poem.txt:6:30:six match
+poem.txt:10:57:ten match
+",
+            render(&["-n", "-b"], Some(Path::new("poem.txt")))
+        );
+    }
+
+    #[test]
+    fn context_groups_merge_and_are_separated() {
+        assert_eq!(
+            "\
+1-one
+2:two match
+3-three
+--
+5-five
+6:six match
+7-seven
+--
+9-nine
+10:ten match
+--
+6:six match
+7-seven
+--
+9-nine
+10:ten match
+",
+            render(&["-n", "-C", "1"], None)
+        );
+
+        assert_eq!(
+            "\
+two match
+three
+four
+five
+six match
+seven
+eight
+nine
+ten match
+--
+six match
+seven
+eight
+nine
+ten match
+",
+            render(&["-A", "3"], None)
+        );
+    }
+
+    #[test]
+    fn colors_paths_numbers_and_matches() {
+        assert_eq!(
+            "\
+\x1b[35mpoem.txt\x1b[0m\x1b[36m:\x1b[0m\x1b[32m2\x1b[0m\x1b[36m:\x1b[0mtwo \x1b[1;31mmatch\x1b[0m
+\x1b[35mpoem.txt\x1b[0m\x1b[36m-\x1b[0m\x1b[32m3\x1b[0m\x1b[36m-\x1b[0mthree
+",
+            render(&["--color=always", "-n", "-A", "1", "-m", "1"], Some(Path::new("poem.txt")))
+                .split("\x1b[36m--")
+                .next()
+                .unwrap()
+        );
+        assert!(!render(&["--color=never"], None).contains('\x1b'));
+    }
+}
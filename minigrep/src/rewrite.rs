@@ -0,0 +1,159 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use memchr::memchr;
+use tempfile::NamedTempFile;
+
+use crate::matcher::Matcher;
+use crate::printer::Printer;
+use crate::{input, lines, Config};
+
+// A selected line whose replacement differs from the original
+pub struct Change<'a> {
+    pub line_number: usize,
+    pub old: &'a str,
+    pub new: String,
+}
+
+// --in-place and --dry-run: applies --replace to the selected lines of
+// path, then either writes the result back or prints the difference. The
+// whole file is read into memory, since it's about to be rewritten anyway.
+// Line terminators are kept exactly as they were. Binary files (ones with
+// NUL bytes) are left alone, and a file that isn't UTF-8 is an error: it
+// couldn't be written back the way it was.
+pub fn file<W: Write>(
+    config: &Config,
+    matcher: &Matcher,
+    printer: &mut Printer<W>,
+    path: &Path,
+) -> io::Result<()> {
+    if input::named_compressed(path) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "compressed, so it can't be rewritten"));
+    }
+    let bytes = fs::read(path)?;
+    if memchr(0, &bytes).is_some() {
+        return Ok(());
+    }
+    let contents = String::from_utf8(bytes).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData, "not UTF-8 text, so it can't be rewritten")
+    })?;
+    let replacement = config.replace.as_deref().unwrap_or_default();
+
+    let mut rewritten = String::with_capacity(contents.len());
+    let mut changes = Vec::new();
+    let mut selected_count = 0;
+
+    // lines() yields one Line per split_inclusive piece, minus its
+    // terminator
+    for (raw, m) in contents.split_inclusive('\n').zip(lines(&contents)) {
+        let terminator = &raw[m.line.len()..];
+
+        let limit_reached = config.max_count == Some(selected_count);
+        let selected = matcher.is_match(m.line) != config.invert_match;
+        if selected && !limit_reached {
+            selected_count += 1;
+            let (new, _) = matcher.replace(m.line, replacement);
+            rewritten.push_str(&new);
+            if new != m.line {
+                changes.push(Change { line_number: m.line_number, old: m.line, new });
+            }
+        } else {
+            rewritten.push_str(m.line);
+        }
+        rewritten.push_str(terminator);
+    }
+
+    if changes.is_empty() {
+        Ok(())
+    } else if config.dry_run {
+        printer.print_diff(path, &changes)
+    } else {
+        replace_contents(path, &rewritten)
+    }
+}
+
+// Writes contents to a temporary file next to path and renames it over
+// path, so a reader sees either the old file or the new one, never half
+// of each, and a failure part way leaves the original untouched.
+fn replace_contents(path: &Path, contents: &str) -> io::Result<()> {
+    // Rewrite the file a symlink points to rather than the link itself
+    let path = fs::canonicalize(path)?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+
+    let mut temp = NamedTempFile::new_in(dir)?;
+    temp.write_all(contents.as_bytes())?;
+    temp.as_file().set_permissions(fs::metadata(&path)?.permissions())?;
+    temp.as_file().sync_all()?;
+    temp.persist(&path).map_err(|err| err.error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_args;
+
+    const POEM: &str = "Rust:\r\nsafe, fast, productive.\nPick three.\nTrust me, rust is fast.";
+
+    // Runs file over a copy of POEM, returning what was printed and what
+    // the file holds afterwards
+    fn rewrite(flags: &[&str]) -> (String, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("poem.txt");
+        fs::write(&path, POEM).unwrap();
+
+        let path_arg = path.display().to_string();
+        let config = parse_args(&[flags, &[path_arg.as_str()]].concat()).unwrap();
+        let matcher = Matcher::new(&config).unwrap();
+        let mut out = Vec::new();
+        let mut printer = Printer::new(&mut out, &config);
+        file(&config, &matcher, &mut printer, &path).unwrap();
+
+        let printed = String::from_utf8(out).unwrap().replace(&path.display().to_string(), "poem.txt");
+        (printed, fs::read_to_string(&path).unwrap())
+    }
+
+    #[test]
+    fn in_place_keeps_everything_but_the_matches() {
+        let (printed, contents) = rewrite(&["-E", "-r", "${1}ust", "--in-place", "(T|R)rust|R(u)st"]);
+
+        assert_eq!("", printed);
+        assert_eq!("ust:\r\nsafe, fast, productive.\nPick three.\nTust me, rust is fast.", contents);
+
+        let (_, contents) = rewrite(&["-m1", "-r", "quick", "--in-place", "fast"]);
+        assert_eq!(POEM.replacen("fast", "quick", 1), contents);
+    }
+
+    #[test]
+    fn binary_and_other_encodings_are_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = parse_args(&["-r", "X", "--in-place", "rust", "x"]).unwrap();
+        let matcher = Matcher::new(&config).unwrap();
+        let mut printer = Printer::new(Vec::new(), &config);
+
+        let binary = dir.path().join("binary");
+        fs::write(&binary, "rust\0junk\n").unwrap();
+        file(&config, &matcher, &mut printer, &binary).unwrap();
+        assert_eq!("rust\0junk\n", fs::read_to_string(&binary).unwrap());
+
+        let latin1 = dir.path().join("latin1");
+        fs::write(&latin1, b"rust caf\xe9\n").unwrap();
+        let err = file(&config, &matcher, &mut printer, &latin1).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert_eq!(b"rust caf\xe9\n", &fs::read(&latin1).unwrap()[..]);
+    }
+
+    #[test]
+    fn dry_run_prints_a_diff_and_changes_nothing() {
+        let (printed, contents) = rewrite(&["-i", "-r", "Go\nlang", "--dry-run", "rust"]);
+
+        assert_eq!(POEM, contents);
+        assert_eq!(
+            "--- poem.txt\n+++ poem.txt\n\
+             @@ -1 +1,2 @@\n-Rust:\n+Go\n+lang:\n\
+             @@ -4 +5,3 @@\n-Trust me, rust is fast.\n+TGo\n+lang me, Go\n+lang is fast.\n",
+            printed
+        );
+    }
+}
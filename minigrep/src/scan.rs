@@ -0,0 +1,142 @@
+use std::iter;
+use std::ops::Range;
+
+use memchr::memmem::Finder;
+use memchr::{memchr, memchr_iter, memrchr};
+
+use crate::{lines, Line};
+
+// The lines of contents that contain finder's needle, the same ones
+// lines(contents).filter(|m| m.line.contains(needle)) would yield, found the
+// other way around: the whole buffer is searched for the needle (with
+// memchr's SIMD substring search), and only around each hit are the line's
+// boundaries and number worked out. Lines without a hit are skipped over
+// without being looked at one by one.
+//
+// The needle mustn't contain a line break, or a hit could span two lines.
+pub fn lines_containing<'a: 'f, 'f>(
+    finder: &'f Finder<'f>,
+    contents: &'a str,
+) -> impl Iterator<Item = Line<'a>> + 'f {
+    spans_containing(finder, contents.as_bytes()).map(|(line_number, span)| Line {
+        line_number,
+        byte_offset: span.start,
+        line: &contents[span],
+    })
+}
+
+// lines_containing for bytes that may not be UTF-8 (a mapped file): the
+// number of each line with a hit, and where it is in bytes, without its
+// line terminator
+pub fn spans_containing<'f>(
+    finder: &'f Finder<'f>,
+    bytes: &'f [u8],
+) -> impl Iterator<Item = (usize, Range<usize>)> + 'f {
+    // Where to search for the next hit: the start of the line after the
+    // last one yielded
+    let mut pos = 0;
+    // The number of the line starting at pos
+    let mut line_number = 1;
+
+    iter::from_fn(move || {
+        // lines() doesn't yield an empty last line after a final \n
+        if pos >= bytes.len() {
+            return None;
+        }
+        let hit = pos + finder.find(&bytes[pos..])?;
+
+        let start = memrchr(b'\n', &bytes[pos..hit]).map_or(pos, |i| pos + i + 1);
+        let end = memchr(b'\n', &bytes[hit..]).map_or(bytes.len(), |i| hit + i);
+        line_number += memchr_iter(b'\n', &bytes[pos..start]).count();
+
+        // Like lines(), a \r only counts as part of the terminator before a \n
+        let trimmed = if end < bytes.len() && end > start && bytes[end - 1] == b'\r' { end - 1 } else { end };
+        let found = (line_number, start..trimmed);
+
+        pos = end + 1;
+        line_number += 1;
+        Some(found)
+    })
+}
+
+// Every line of contents, along with the parts of it that spans cover, as
+// byte ranges of the line. A line is spanned when any part of it is, line
+// terminator included; a span of just the terminator leaves an empty range
+// at the end of the line. spans must be sorted and not overlap, as
+// Matcher::find_iter returns them; a span that runs over a line break
+// covers the lines on both sides of it.
+pub fn lines_spanned<'a>(
+    spans: &'a [Range<usize>],
+    contents: &'a str,
+) -> impl Iterator<Item = (Line<'a>, Vec<Range<usize>>)> + 'a {
+    let mut lines = lines(contents).peekable();
+    let mut first = 0;
+
+    iter::from_fn(move || {
+        let m = lines.next()?;
+        let end = lines.peek().map_or(contents.len(), |next| next.byte_offset);
+
+        while spans.get(first).is_some_and(|span| span.end <= m.byte_offset) {
+            first += 1;
+        }
+        let (start, text_end) = (m.byte_offset, m.byte_offset + m.line.len());
+        let parts = spans[first..]
+            .iter()
+            .take_while(|span| span.start < end)
+            .map(|span| {
+                let from = span.start.clamp(start, text_end);
+                from - start..span.end.clamp(from, text_end) - start
+            })
+            .collect();
+        Some((m, parts))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_agrees(needle: &str, contents: &str) {
+        let finder = Finder::new(needle);
+        let scanned: Vec<_> = lines_containing(&finder, contents).collect();
+        let filtered: Vec<_> = lines(contents).filter(|m| m.line.contains(needle)).collect();
+
+        assert_eq!(filtered, scanned, "needle {:?} in {:?}", needle, contents);
+    }
+
+    #[test]
+    fn agrees_with_filtering_lines() {
+        let contents = "Rust:\r\nsafe, fast, productive.\nPick three.\n\nTrust me, rust is fast, fast.\r\nfast";
+
+        for needle in ["fast", "Rust", "rust", ":", ".", "t", "", "missing", "Pick three."] {
+            assert_agrees(needle, contents);
+        }
+    }
+
+    #[test]
+    fn edges_of_the_buffer() {
+        for contents in ["", "\n", "fast", "fast\n", "\nfast\n\n", "slow\r\n"] {
+            assert_agrees("fast", contents);
+            assert_agrees("", contents);
+        }
+    }
+
+    #[test]
+    fn spans_select_every_line_they_touch() {
+        let contents = "one\ntwo\r\nthree\nfour\nfive";
+        let spanned = |spans: &[Range<usize>]| -> Vec<usize> {
+            lines_spanned(spans, contents).filter(|(_, parts)| !parts.is_empty()).map(|(m, _)| m.line_number).collect()
+        };
+
+        // "o\ntw" and "ve"
+        assert_eq!(vec![1, 2, 5], spanned(&[2..6, 22..24]));
+        // A match of the line break at the end of "two" stays on its line
+        assert_eq!(vec![2], spanned(&[Range { start: 7, end: 9 }]));
+        assert_eq!(vec![3, 4], spanned(&[Range { start: 12, end: 18 }]));
+        assert!(spanned(&[]).is_empty());
+        assert_eq!(5, lines_spanned(&[], contents).count());
+
+        let parts: Vec<_> = lines_spanned(&[2..6, Range { start: 7, end: 9 }], contents).map(|(_, parts)| parts).collect();
+        assert_eq!(vec![vec![2..3], vec![0..2, 3..3], vec![], vec![], vec![]], parts);
+    }
+}
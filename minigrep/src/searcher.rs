@@ -0,0 +1,188 @@
+use std::io::{self, BufRead};
+use std::ops::Range;
+use std::path::Path;
+
+use crate::input::Input;
+use crate::{feed, lines, scan, ArgsError, Config, Line, Matcher, Sink};
+
+// One match of the patterns, as yielded by Searcher::matches. byte_range
+// is where the match is in the searched text, and line is the whole line
+// it's on, without its terminator. (Line, which sinks are given, describes
+// a line instead.)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Match<'a> {
+    pub line_number: usize,
+    pub byte_range: Range<usize>,
+    pub line: &'a str,
+}
+
+// The search engine behind the command line, for programs that want the
+// results themselves rather than minigrep's output on stdout. A Searcher
+// is built from a Config, usually from Config::from_args with the same
+// flags the command line takes:
+//
+//     let args = ["-i", "rust"].map(String::from);
+//     let searcher = Searcher::new(Config::from_args(args.into_iter())?)?;
+//     for m in searcher.matches(contents) {
+//         println!("{}:{:?}", m.line_number, m.byte_range);
+//     }
+pub struct Searcher {
+    config: Config,
+    matcher: Matcher,
+}
+
+impl Searcher {
+    // Fails for a Config the command line would have refused, such as
+    // --fuzzy without exactly one pattern
+    pub fn new(config: Config) -> Result<Searcher, ArgsError> {
+        let matcher = Matcher::new(&config)?;
+        Ok(Searcher { config, matcher })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn matcher(&self) -> &Matcher {
+        &self.matcher
+    }
+
+    // Every match in contents, found as the iterator is advanced, so
+    // stopping early skips the rest of the search. Matches are looked for
+    // within lines; the flags that change which lines are selected (-v,
+    // -U, -m, --field) are for search_path and search_reader.
+    pub fn matches<'s, 'a: 's>(&'s self, contents: &'a str) -> impl Iterator<Item = Match<'a>> + 's {
+        let lines: Box<dyn Iterator<Item = Line<'a>> + 's> = match self.matcher.finder() {
+            Some(finder) => Box::new(scan::lines_containing(finder, contents)),
+            None => Box::new(lines(contents)),
+        };
+
+        lines.flat_map(move |m| {
+            self.matcher.find_iter(m.line).into_iter().map(move |range| Match {
+                line_number: m.line_number,
+                byte_range: m.byte_offset + range.start..m.byte_offset + range.end,
+                line: m.line,
+            })
+        })
+    }
+
+    // Searches the file at path ("-" is standard input) and hands every
+    // line to sink, with whether it's selected, the way the command line
+    // would: compressed files and encodings are handled as the Config
+    // says, and -v, -U and -m apply. Binary files aren't treated
+    // specially; that's up to the sink.
+    pub fn search_path(&self, path: &Path, sink: &mut dyn Sink) -> io::Result<()> {
+        let input = Input::open(path, self.config.search_zip, self.config.encoding, self.config.mmap)?;
+        feed(&self.config, &self.matcher, input, sink)
+    }
+
+    // Like search_path, for text that is already open. It's read as UTF-8,
+    // a line at a time.
+    pub fn search_reader<'r>(&self, reader: impl BufRead + 'r, sink: &mut dyn Sink) -> io::Result<()> {
+        feed(&self.config, &self.matcher, Input::Stream(Box::new(reader)), sink)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_args;
+
+    const CONTENTS: &str = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Trust me, rust is fast.";
+
+    fn searcher(flags: &[&str]) -> Searcher {
+        Searcher::new(parse_args(flags).unwrap()).unwrap()
+    }
+
+    // A sink that keeps what it's given, and counts the calls to finish
+    #[derive(Default)]
+    struct Collect {
+        lines: Vec<(usize, String, bool)>,
+        finished: usize,
+    }
+
+    impl Sink for Collect {
+        fn line(&mut self, m: &Line, selected: bool) -> io::Result<bool> {
+            self.lines.push((m.line_number, m.line.to_string(), selected));
+            Ok(true)
+        }
+
+        fn finish(&mut self) -> io::Result<()> {
+            self.finished += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn matches_have_line_numbers_and_byte_ranges() {
+        let found: Vec<_> = searcher(&["fast"]).matches(CONTENTS).collect();
+
+        assert_eq!(
+            vec![
+                Match { line_number: 2, byte_range: 12..16, line: "safe, fast, productive." },
+                Match { line_number: 4, byte_range: 60..64, line: "Trust me, rust is fast." },
+            ],
+            found
+        );
+        for m in &found {
+            assert_eq!("fast", &CONTENTS[m.byte_range.clone()]);
+        }
+
+        let searcher = searcher(&["-i", "RUST"]);
+        let mut found = searcher.matches(CONTENTS);
+        assert_eq!(Some(0..4), found.next().map(|m| m.byte_range));
+        assert_eq!(Some(43..47), found.next().map(|m| m.byte_range));
+    }
+
+    #[test]
+    fn sinks_get_every_line_and_whether_it_is_selected() {
+        let mut sink = Collect::default();
+        searcher(&["-v", "-m", "2", "fast"]).search_reader(CONTENTS.as_bytes(), &mut sink).unwrap();
+
+        assert_eq!(
+            vec![
+                (1, "Rust:".to_string(), true),
+                (2, "safe, fast, productive.".to_string(), false),
+                (3, "Pick three.".to_string(), true),
+            ],
+            sink.lines
+        );
+        assert_eq!(1, sink.finished);
+    }
+
+    #[test]
+    fn readers_may_borrow() {
+        let contents = CONTENTS.replace("fast", "quick");
+        let mut sink = Collect::default();
+        searcher(&["quick"]).search_reader(contents.as_bytes(), &mut sink).unwrap();
+
+        let selected = sink.lines.iter().filter(|(_, _, selected)| *selected).count();
+        assert_eq!(2, selected);
+    }
+
+    #[test]
+    fn configs_the_command_line_would_refuse_are_errors() {
+        let config = Config { fuzzy: Some(1), ..Config::default() };
+        assert!(matches!(Searcher::new(config), Err(ArgsError::Requires { flag: "--fuzzy", .. })));
+
+        let long = "x".repeat(100);
+        let config = Config { fuzzy: Some(1), patterns: vec![long], ..Config::default() };
+        assert!(matches!(Searcher::new(config), Err(ArgsError::TooLong { flag: "--fuzzy", max: 64 })));
+    }
+
+    #[test]
+    fn searches_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("poem.txt");
+        std::fs::write(&path, CONTENTS).unwrap();
+
+        let mut sink = Collect::default();
+        searcher(&["-x", "Pick three."]).search_path(&path, &mut sink).unwrap();
+        let selected: Vec<_> = sink.lines.iter().filter(|(_, _, selected)| *selected).collect();
+        assert_eq!(vec![&(3, "Pick three.".to_string(), true)], selected);
+    }
+}
@@ -0,0 +1,385 @@
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use crate::matcher::Matcher;
+use crate::printer::{Notes, Printer};
+use crate::{Config, Line};
+
+// Where the lines of one file go once it's known whether they're selected.
+// The search runs the matcher over every line and hands the result to one
+// of these; each output mode of the command line is a separate Sink, and
+// programs using Searcher can bring their own.
+pub trait Sink {
+    // Takes the next line of the file. Returning false means the sink has
+    // seen enough and the rest of the file doesn't need to be read.
+    fn line(&mut self, m: &Line, selected: bool) -> io::Result<bool>;
+
+    // Takes a line that -U selected because the matches at ranges (byte
+    // ranges of m.line) cover part of it. A match that runs over a line
+    // break can't be found again in one line, so where it is comes along.
+    fn spanned(&mut self, m: &Line, ranges: &[Range<usize>]) -> io::Result<bool> {
+        let _ = ranges;
+        self.line(m, true)
+    }
+
+    // Called once the file has been read
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Picks the sink for the output mode in config. show_path says whether
+// lines should be prefixed with the file name, and binary whether the file
+// looks binary, so its lines aren't worth printing.
+pub fn for_config<'a, W: Write>(
+    config: &'a Config,
+    matcher: &'a Matcher,
+    printer: &'a mut Printer<W>,
+    path: &'a Path,
+    show_path: bool,
+    binary: bool,
+) -> Box<dyn Sink + 'a> {
+    let prefix = if show_path { Some(path) } else { None };
+    let patterns = if config.show_pattern { Some(&config.patterns[..]) } else { None };
+
+    // -c, -l and -L say the same of a binary file as of any other; the
+    // rest would print its lines
+    let listing = !config.json && (config.count || config.files_with_matches || config.files_without_match);
+
+    if binary && !listing {
+        Box::new(Binary { printer, path, any: false })
+    } else if config.json {
+        // JSON events always say which file they are about, and every
+        // other output mode can be derived from them
+        printer.begin_file();
+        let highlight = !config.invert_match;
+        Box::new(Lines { printer, matcher, highlight, replacement: None, patterns, prefix: Some(path) })
+    } else if config.files_with_matches || config.files_without_match {
+        Box::new(Files { printer, path, with_matches: config.files_with_matches, any: false })
+    } else if config.count {
+        Box::new(Count { printer, prefix, count: 0 })
+    } else if config.only_matching {
+        let replacement = config.replace.as_deref();
+        Box::new(OnlyMatching { printer, matcher, replacement, patterns, prefix })
+    } else {
+        printer.begin_file();
+        // Only lines that matched have anything to highlight
+        let highlight = printer.wants_matches() && !config.invert_match;
+        let replacement = config.replace.as_deref();
+        Box::new(Lines { printer, matcher, highlight, replacement, patterns, prefix })
+    }
+}
+
+// The default: selected lines, plus any context lines asked for
+struct Lines<'a, W> {
+    printer: &'a mut Printer<W>,
+    matcher: &'a Matcher,
+    // Whether the matches in selected lines should be located, for
+    // highlighting or for JSON output
+    highlight: bool,
+    // --replace: selected lines are printed with their matches replaced
+    replacement: Option<&'a str>,
+    // --show-pattern: all the patterns, to name the ones that matched
+    patterns: Option<&'a [String]>,
+    prefix: Option<&'a Path>,
+}
+
+impl<W: Write> Sink for Lines<'_, W> {
+    fn line(&mut self, m: &Line, selected: bool) -> io::Result<bool> {
+        if !selected {
+            self.printer.line(self.prefix, m, false, &[], &Notes::default())?;
+            return Ok(true);
+        }
+
+        let notes = notes(self.matcher, self.patterns, m.line);
+        if let Some(replacement) = self.replacement {
+            let (line, ranges) = self.matcher.replace(m.line, replacement);
+            let replaced = Line { line: &line, ..*m };
+            let highlight: Vec<_> = match self.highlight {
+                true => ranges.into_iter().map(|(_, to)| to).collect(),
+                false => Vec::new(),
+            };
+            self.printer.line(self.prefix, &replaced, true, &highlight, &notes)?;
+        } else {
+            let highlight = match self.highlight {
+                true => self.matcher.find_iter(m.line),
+                false => Vec::new(),
+            };
+            self.printer.line(self.prefix, m, true, &highlight, &notes)?;
+        }
+        Ok(true)
+    }
+
+    fn spanned(&mut self, m: &Line, ranges: &[Range<usize>]) -> io::Result<bool> {
+        let notes = notes(self.matcher, self.patterns, m.line);
+        let highlight = if self.highlight { ranges } else { &[] };
+        self.printer.line(self.prefix, m, true, highlight, &notes)?;
+        Ok(true)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.printer.end_file(self.prefix)
+    }
+}
+
+// -o: each matched part of a selected line, one per output line
+struct OnlyMatching<'a, W> {
+    printer: &'a mut Printer<W>,
+    matcher: &'a Matcher,
+    // --replace: each match is printed as its replacement instead
+    replacement: Option<&'a str>,
+    patterns: Option<&'a [String]>,
+    prefix: Option<&'a Path>,
+}
+
+impl<W: Write> Sink for OnlyMatching<'_, W> {
+    fn line(&mut self, m: &Line, selected: bool) -> io::Result<bool> {
+        // With -v the selected lines are the ones without a match, so
+        // there is nothing to print for them
+        if !selected {
+            return Ok(true);
+        }
+
+        // The byte offset is that of the match rather than of the line
+        let part = |range: &std::ops::Range<usize>, text| Line {
+            line_number: m.line_number,
+            byte_offset: m.byte_offset + range.start,
+            line: text,
+        };
+        match self.replacement {
+            Some(replacement) => {
+                let (line, ranges) = self.matcher.replace(m.line, replacement);
+                for (from, to) in ranges {
+                    let notes = notes(self.matcher, self.patterns, &m.line[from.clone()]);
+                    self.printer.matched_part(self.prefix, &part(&from, &line[to]), &notes)?;
+                }
+            }
+            None => {
+                for range in self.matcher.find_iter(m.line) {
+                    let text = &m.line[range.clone()];
+                    let notes = notes(self.matcher, self.patterns, text);
+                    self.printer.matched_part(self.prefix, &part(&range, text), &notes)?;
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+// The notes for text: the patterns that matched it, if --show-pattern
+// asked for them, and with --fuzzy how close it came to the query
+fn notes<'p>(matcher: &Matcher, patterns: Option<&'p [String]>, text: &str) -> Notes<'p> {
+    let patterns = match patterns {
+        Some(patterns) => matcher.patterns_in(text).into_iter().map(|i| patterns[i].as_str()).collect(),
+        None => Vec::new(),
+    };
+    Notes { patterns, distance: matcher.distance(text) }
+}
+
+// -c: the number of selected lines
+struct Count<'a, W> {
+    printer: &'a mut Printer<W>,
+    prefix: Option<&'a Path>,
+    count: usize,
+}
+
+impl<W: Write> Sink for Count<'_, W> {
+    fn line(&mut self, _: &Line, selected: bool) -> io::Result<bool> {
+        if selected {
+            self.count += 1;
+        }
+        Ok(true)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.printer.print_count(self.prefix, self.count)
+    }
+}
+
+// -l / -L: the file name, if the file has (or lacks) a selected line
+struct Files<'a, W> {
+    printer: &'a mut Printer<W>,
+    path: &'a Path,
+    with_matches: bool,
+    any: bool,
+}
+
+impl<W: Write> Sink for Files<'_, W> {
+    fn line(&mut self, _: &Line, selected: bool) -> io::Result<bool> {
+        // The first selected line settles it either way
+        self.any |= selected;
+        Ok(!self.any)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if self.any == self.with_matches {
+            self.printer.print_path(self.path)?;
+        }
+        Ok(())
+    }
+}
+
+// A binary file: one line saying whether it has a selected line, as GNU
+// grep does, rather than lines of garbage that could upset the terminal
+struct Binary<'a, W> {
+    printer: &'a mut Printer<W>,
+    path: &'a Path,
+    any: bool,
+}
+
+impl<W: Write> Sink for Binary<'_, W> {
+    fn line(&mut self, _: &Line, selected: bool) -> io::Result<bool> {
+        self.any |= selected;
+        Ok(!self.any)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if self.any {
+            self.printer.print_binary_match(self.path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::Input;
+    use crate::parse_args;
+    use crate::search_input;
+
+    const CONTENTS: &str = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Trust me, rust is fast.";
+
+    fn output(flags: &[&str]) -> String {
+        output_for(flags, CONTENTS)
+    }
+
+    fn output_for(flags: &[&str], contents: &str) -> String {
+        let config = parse_args(flags).unwrap();
+        let matcher = Matcher::new(&config).unwrap();
+
+        let mut out = Vec::new();
+        let mut printer = Printer::new(&mut out, &config);
+        let input = Input::Text(contents.to_string());
+        search_input(&config, &matcher, &mut printer, Path::new("poem.txt"), true, input).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn only_matching_prints_each_match() {
+        assert_eq!(
+            "poem.txt:1:0:Rust\npoem.txt:4:43:rust\npoem.txt:4:52:rust\n",
+            output(&["-onb", "-e", "rust", "-e", "Rust"])
+        );
+        assert_eq!("poem.txt:fast\npoem.txt:fast\n", output(&["-o", "fast"]));
+        assert_eq!("", output(&["-ov", "fast"]));
+    }
+
+    #[test]
+    fn count_and_max_count() {
+        assert_eq!("poem.txt:2\n", output(&["-c", "fast"]));
+        assert_eq!("poem.txt:2\n", output(&["-cv", "fast"]));
+        assert_eq!("poem.txt:1\n", output(&["-c", "-m", "1", "fast"]));
+        assert_eq!("poem.txt:safe, fast, productive.\n", output(&["-m1", "fast"]));
+        assert_eq!("", output(&["-m", "0", "fast"]));
+        assert_eq!("poem.txt:0\n", output(&["-c", "-m", "0", "fast"]));
+        // The context after the last selected line is still printed, even
+        // a line that would have been selected
+        assert_eq!(
+            "poem.txt:safe, fast, productive.\npoem.txt-Pick three.\npoem.txt-Trust me, rust is fast.\n",
+            output(&["-m1", "-A2", "fast"])
+        );
+    }
+
+    #[test]
+    fn files_with_and_without_matches() {
+        assert_eq!("poem.txt\n", output(&["-l", "three"]));
+        assert_eq!("", output(&["-l", "four"]));
+        assert_eq!("", output(&["-L", "three"]));
+        assert_eq!("poem.txt\n", output(&["-L", "four"]));
+    }
+
+    #[test]
+    fn replace_rewrites_selected_lines() {
+        assert_eq!(
+            "poem.txt:2:safe, quick, productive.\npoem.txt-3-Pick three.\npoem.txt:4:Trust me, rust is quick.\n",
+            output(&["-n", "-A1", "-r", "quick", "fast"])
+        );
+        assert_eq!(
+            "poem.txt:T<u|rust> me, <u|rust> is fast.\n",
+            output(&["-E", "-r", "<${1}|$0>", "r(u)st"])
+        );
+        assert_eq!("poem.txt:12:F\npoem.txt:60:F\n", output(&["-ob", "-r", "F", "fast"]));
+    }
+
+    #[test]
+    fn show_pattern_names_the_patterns_that_matched() {
+        assert_eq!(
+            "poem.txt:fast:safe, fast, productive.\npoem.txt:rust,fast:Trust me, rust is fast.\n",
+            output(&["--show-pattern", "-e", "fast", "-e", "rust", "-e", "slow"])
+        );
+        assert_eq!(
+            "poem.txt:R[a-z]st:Rust:\npoem.txt:rust:Trust me, rust is fast.\n",
+            output(&["--show-pattern", "-E", "-e", "R[a-z]st", "-e", "rust", "-e", "^Pick$"])
+        );
+        // With -o, each part is labelled with the pattern it matched
+        assert_eq!(
+            "poem.txt:2:fast:fast\npoem.txt:4:rust:rust\npoem.txt:4:rust:rust\npoem.txt:4:fast:fast\n",
+            output(&["-on", "--show-pattern", "-e", "rust", "-e", "fast"])
+        );
+    }
+
+    #[test]
+    fn fuzzy_notes_the_distance() {
+        assert_eq!("poem.txt:~1:Pick three.\n", output(&["--fuzzy", "1", "tree"]));
+        assert_eq!(
+            "poem.txt:~1:Rust:\npoem.txt:~0:Trust me, rust is fast.\n",
+            output(&["--fuzzy", "1", "-i", "rust "])
+        );
+        assert_eq!("poem.txt:4:~1:Tru\n", output(&["-on", "--fuzzy=1", "-i", "tru3"]));
+        assert_eq!("", output(&["--fuzzy", "1", "productivity"]));
+    }
+
+    #[test]
+    fn binary_files_only_say_that_they_match() {
+        let contents = "\x7fELF\0\0\0\nrust_begin_unwind\0panic\n";
+
+        assert_eq!("Binary file poem.txt matches\n", output_for(&["rust"], contents));
+        assert_eq!("", output_for(&["python"], contents));
+        assert_eq!("poem.txt:1\n", output_for(&["-c", "rust"], contents));
+        assert_eq!("poem.txt:rust_begin_unwind\0panic\n", output_for(&["-a", "rust"], contents));
+        assert_eq!(
+            "{\"type\":\"binary\",\"data\":{\"path\":\"poem.txt\"}}\n",
+            output_for(&["--json", "rust"], contents)
+        );
+    }
+
+    #[test]
+    fn json_events_describe_each_line() {
+        let events: Vec<serde_json::Value> = output(&["--json", "-A1", "-e", "fast", "-e", "rust"])
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        let kinds: Vec<&str> = events.iter().map(|e| e["type"].as_str().unwrap()).collect();
+        assert_eq!(vec!["begin", "match", "context", "match", "end"], kinds);
+
+        assert_eq!("poem.txt", events[0]["data"]["path"]);
+        assert_eq!(2, events[1]["data"]["line_number"]);
+        assert_eq!(7, events[1]["data"]["column"]);
+        assert_eq!(6, events[1]["data"]["absolute_offset"]);
+        assert_eq!(
+            serde_json::json!([{ "match": "fast", "start": 6, "end": 10 }]),
+            events[1]["data"]["submatches"]
+        );
+        assert_eq!("Pick three.", events[2]["data"]["line"]);
+        assert!(events[2]["data"]["column"].is_null());
+        assert_eq!(3, events[3]["data"]["submatches"].as_array().unwrap().len());
+        assert_eq!(serde_json::json!({ "matched_lines": 2, "matches": 4 }), events[4]["data"]["stats"]);
+    }
+}
@@ -0,0 +1,173 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use ignore::overrides::{Override, OverrideBuilder};
+use ignore::types::{Types, TypesBuilder};
+use ignore::WalkBuilder;
+
+use crate::Config;
+
+// Recursively collects the files under root that should be searched.
+//
+// The ignore crate does the heavy lifting: it reads .gitignore, .ignore
+// and git's global excludes as it descends, and skips hidden entries
+// (names starting with a dot) unless asked not to. Entries are sorted by
+// file name so the output order is the same on every run. The --glob and
+// file type filters are applied on top of the ignore rules.
+pub fn files(root: &Path, config: &Config) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let no_ignore = config.no_ignore;
+    let walker = WalkBuilder::new(root)
+        .hidden(!config.hidden)
+        .ignore(!no_ignore)
+        .git_ignore(!no_ignore)
+        .git_global(!no_ignore)
+        .git_exclude(!no_ignore)
+        .parents(!no_ignore)
+        // Honor .gitignore files even when the tree isn't a git checkout
+        .require_git(false)
+        .overrides(overrides(root, &config.globs)?)
+        .types(types(config)?)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+
+    let mut files = Vec::new();
+    for entry in walker {
+        // A single unreadable directory shouldn't abort the whole search,
+        // so report it and keep walking
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                eprintln!("minigrep: {}", err);
+                continue;
+            }
+        };
+
+        if entry.file_type().is_some_and(|t| t.is_file()) {
+            files.push(entry.into_path());
+        }
+    }
+
+    Ok(files)
+}
+
+// Checks that the --glob and file type flags make sense, so mistakes are
+// reported before anything is searched
+pub fn check_filters(config: &Config) -> Result<(), ignore::Error> {
+    overrides(Path::new("."), &config.globs)?;
+    types(config)?;
+    Ok(())
+}
+
+// --glob: paths under root to include, or with a leading ! to exclude.
+// Once any include glob is given, only files matching one are searched.
+// A later glob takes precedence over an earlier one.
+fn overrides(root: &Path, globs: &[String]) -> Result<Override, ignore::Error> {
+    let mut builder = OverrideBuilder::new(root);
+    for glob in globs {
+        builder.add(glob)?;
+    }
+    builder.build()
+}
+
+// -t / -T: file types to search or to skip, by name. The names come from
+// the ignore crate's built-in table (rust, json, py, md, ...), plus any
+// defined with --type-add NAME:GLOB.
+fn types(config: &Config) -> Result<Types, ignore::Error> {
+    let mut builder = TypesBuilder::new();
+    builder.add_defaults();
+    for def in &config.type_defs {
+        builder.add_def(def)?;
+    }
+    for name in &config.types {
+        builder.select(name);
+    }
+    for name in &config.types_not {
+        builder.negate(name);
+    }
+    builder.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_args;
+    use std::fs;
+
+    fn tree() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src/nested")).unwrap();
+        fs::create_dir_all(dir.path().join("target")).unwrap();
+        fs::write(dir.path().join(".gitignore"), "target/\n*.log\n").unwrap();
+        fs::write(dir.path().join(".ignore"), "secret.txt\n").unwrap();
+        fs::write(dir.path().join(".hidden"), "").unwrap();
+        fs::write(dir.path().join("src/main.rs"), "").unwrap();
+        fs::write(dir.path().join("src/nested/deep.rs"), "").unwrap();
+        fs::write(dir.path().join("src/debug.log"), "").unwrap();
+        fs::write(dir.path().join("secret.txt"), "").unwrap();
+        fs::write(dir.path().join("target/out.rs"), "").unwrap();
+        dir
+    }
+
+    fn walk(root: &Path, flags: &[&str]) -> Vec<String> {
+        let config = parse_args(&[&["pattern"], flags].concat()).unwrap();
+        names(root, files(root, &config).unwrap())
+    }
+
+    fn names(root: &Path, files: Vec<PathBuf>) -> Vec<String> {
+        files
+            .iter()
+            .map(|f| f.strip_prefix(root).unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn honors_ignore_files_and_hidden_rules() {
+        let dir = tree();
+
+        assert_eq!(vec!["src/main.rs", "src/nested/deep.rs"], walk(dir.path(), &[]));
+    }
+
+    #[test]
+    fn hidden_and_no_ignore_widen_the_walk() {
+        let dir = tree();
+
+        assert_eq!(
+            vec![
+                ".gitignore",
+                ".hidden",
+                ".ignore",
+                "secret.txt",
+                "src/debug.log",
+                "src/main.rs",
+                "src/nested/deep.rs",
+                "target/out.rs",
+            ],
+            walk(dir.path(), &["--hidden", "--no-ignore"])
+        );
+    }
+
+    #[test]
+    fn globs_include_and_exclude() {
+        let dir = tree();
+
+        assert_eq!(vec!["src/nested/deep.rs"], walk(dir.path(), &["-g", "**/nested/*"]));
+        assert_eq!(
+            vec!["src/debug.log", "src/main.rs"],
+            walk(dir.path(), &["--no-ignore", "-g", "src/**", "--glob", "!**/nested/**"])
+        );
+        assert_eq!(vec!["secret.txt"], walk(dir.path(), &["--no-ignore", "-g", "!src/**", "-g", "!target/**"]));
+    }
+
+    #[test]
+    fn file_types_select_and_negate() {
+        let dir = tree();
+        fs::write(dir.path().join("src/data.json"), "").unwrap();
+
+        assert_eq!(vec!["src/main.rs", "src/nested/deep.rs"], walk(dir.path(), &["-t", "rust"]));
+        assert_eq!(vec!["src/data.json"], walk(dir.path(), &["-T", "rust"]));
+        assert_eq!(
+            vec!["src/debug.log"],
+            walk(dir.path(), &["--no-ignore", "--type-add", "logs:*.log", "-tlogs"])
+        );
+    }
+}